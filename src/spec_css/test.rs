@@ -5,6 +5,7 @@ extern mod extra;
 
 use spec_css::*;
 use css_lex::*;
+use speculate::WorkerPool;
 use extra::arc::Arc;
 use extra::json;
 use extra::json::ToJson;
@@ -58,3 +59,12 @@ fn test_spec_token_json() {
         list_to_json(&spec_tokenize(input, 3))
     }
 }
+
+#[test]
+fn test_spec_token_json_pool() {
+    let pool = WorkerPool::new(2);
+    do run_json_tests(include_str!("../css_lex/css-lexing-tests/tokens.json")) |input| {
+        let (_, tokens) = spec_tokenize_on(&pool, input, 4);
+        list_to_json(&tokens)
+    }
+}
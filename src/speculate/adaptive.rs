@@ -0,0 +1,144 @@
+use std::num;
+use extra::time::precise_time_ns;
+use super::{SpecStats, TaskExecutor, ignore_output, spec_by, specfold_sequential,
+            specfold_window_on};
+
+// Weight given to the newest sample in the moving averages
+static SMOOTHING: float = 0.25;
+
+// How many sequential calls to make before trying to speculate again, in case
+// the predictor has become more accurate
+static PROBE_INTERVAL: uint = 16;
+
+fn smooth(average: Option<float>, sample: float) -> Option<float> {
+    match average {
+        Some(avg) => Some(avg + SMOOTHING * (sample - avg)),
+        None => Some(sample)
+    }
+}
+
+/**
+ * Decides whether speculation is paying off at one call site.
+ *
+ * Keep one `Adaptive` per call site and call `spec` or `specfold` through it.
+ * It tracks how long calls took per iteration when speculating and when
+ * running sequentially. After trying each way once, it runs sequentially
+ * whenever that has been faster. Every `PROBE_INTERVAL` sequential calls it
+ * speculates again to find out whether the predictor has improved.
+ *
+ * It also tracks the misprediction rate from the `SpecStats` of past calls.
+ * A loop which does speculate only keeps as many iterations in flight as the
+ * rate suggests will be predicted right, so a poor predictor wastes fewer
+ * runs even before the timings show it is not worth speculating at all.
+ */
+pub struct Adaptive {
+    // Moving averages of the time per iteration, in nanoseconds
+    priv spec_ns: Option<float>,
+    priv seq_ns: Option<float>,
+    // Moving average of the fraction of iterations which were mispredicted
+    priv mispredict_rate: Option<float>,
+    // Sequential calls since the last speculative one
+    priv since_spec: uint
+}
+
+impl Adaptive {
+    pub fn new() -> Adaptive {
+        Adaptive { spec_ns: None, seq_ns: None, mispredict_rate: None, since_spec: 0 }
+    }
+
+    pub fn mispredict_rate(&self) -> float {
+        match self.mispredict_rate {
+            Some(rate) => rate,
+            None => 0.0
+        }
+    }
+
+    /**
+     * Whether the next call should speculate.
+     */
+    pub fn should_speculate(&self) -> bool {
+        match (self.spec_ns, self.seq_ns) {
+            (None, _) => true,
+            (Some(_), None) => false,
+            (Some(spec), Some(seq)) => spec < seq || self.since_spec >= PROBE_INTERVAL
+        }
+    }
+
+    /**
+     * How many iterations of a loop of `iters` iterations to keep in flight
+     * at once when speculating: all of them at first, and fewer the more
+     * iterations have been mispredicted, down to one.
+     */
+    pub fn window(&self, iters: uint) -> uint {
+        let expected = (1.0 - self.mispredict_rate()) * iters as float;
+        num::max(expected as uint, 1)
+    }
+
+    /**
+     * Record how a call went. `ns` is how long the whole call took.
+     */
+    pub fn record(&mut self, stats: &SpecStats, ns: u64) {
+        let iters = num::max(stats.iters, 1) as float;
+        let per_iter = ns as float / iters;
+        if stats.sequential {
+            self.seq_ns = smooth(self.seq_ns, per_iter);
+            self.since_spec += 1;
+        } else {
+            let mispredicted = stats.mispredictions.iter().count(|m| *m);
+            self.spec_ns = smooth(self.spec_ns, per_iter);
+            self.mispredict_rate = smooth(self.mispredict_rate, mispredicted as float / iters);
+            self.since_spec = 0;
+        }
+    }
+
+    /**
+     * Like `speculate::spec`, but just run `consumer(producer())` if
+     * speculation has not been paying off.
+     */
+    pub fn spec<A: Eq + Send + Clone, B>(&mut self,
+                                         producer: ~fn() -> A,
+                                         predictor: ~fn() -> A,
+                                         consumer:  ~fn(A) -> B) -> B {
+        let mut stats = SpecStats::new(1);
+        let start = precise_time_ns();
+        let res = if self.should_speculate() {
+            let mut correct = true;
+            let res = spec_by(producer, predictor, consumer, |p, a| {
+                correct = *p == *a;
+                correct
+            });
+            stats.mispredictions[0] = !correct;
+            res
+        } else {
+            stats.sequential = true;
+            consumer(producer())
+        };
+        self.record(&stats, precise_time_ns() - start);
+        res
+    }
+
+    /**
+     * Like `speculate::specfold_collect`, but run the loop sequentially in the
+     * calling task if speculation has not been paying off, and otherwise with
+     * at most `window(iters)` iterations in flight. The initial value is still
+     * taken from `predictor`.
+     */
+    pub fn specfold<A: Eq + Clone + Send>(&mut self,
+                                          iters: uint,
+                                          loop_body: &fn() -> ~fn(uint, A) -> A,
+                                          predictor: &fn() -> ~fn(uint) -> A)
+                                          -> (SpecStats, ~[A]) {
+        let start = precise_time_ns();
+        let (stats, results) = if self.should_speculate() {
+            let mut results = ~[];
+            let stats = specfold_window_on(&TaskExecutor, self.window(iters), iters,
+                                           || ignore_output(loop_body()), predictor,
+                                           |p, a| *p == *a, |_, res, _| results.push(res));
+            (stats, results)
+        } else {
+            specfold_sequential(iters, loop_body, predictor)
+        };
+        self.record(&stats, precise_time_ns() - start);
+        (stats, results)
+    }
+}
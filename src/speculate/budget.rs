@@ -0,0 +1,115 @@
+use std::num;
+use extra::arc::RWArc;
+use extra::time::precise_time_ns;
+
+use super::{SpecStats, TaskExecutor, ignore_output, spec_timed, specfold_sequential,
+            specfold_window_on};
+
+/**
+ * A number of speculative slots shared by nested speculative calls.
+ *
+ * Hand a clone to every loop body or consumer which speculates itself, and
+ * make all speculative calls through it. Each call takes as many slots as it
+ * could use, up to however many are free, and gives them back when it
+ * returns: `spec` needs one for its producer, and `specfold` runs at most one
+ * iteration per slot at once. A call which gets no slots at all runs
+ * sequentially in the calling task, so inner levels degrade gracefully once
+ * outer levels have used up the budget.
+ *
+ * The stats of each call record how many slots it got in `slots`, and
+ * whether it ran sequentially in `sequential`.
+ */
+#[deriving(Clone)]
+pub struct Budget {
+    priv free: RWArc<uint>
+}
+
+// Slots taken from a budget, which are given back when this is dropped
+struct Slots {
+    budget: Budget,
+    count: uint
+}
+
+impl Drop for Slots {
+    fn drop(&mut self) {
+        self.budget.release(self.count);
+    }
+}
+
+impl Budget {
+    pub fn new(slots: uint) -> Budget {
+        Budget { free: RWArc::new(slots) }
+    }
+
+    /**
+     * How many slots are free right now.
+     */
+    pub fn available(&self) -> uint {
+        self.free.read(|free| *free)
+    }
+
+    /**
+     * Take up to `wanted` slots, returning how many were taken.
+     */
+    pub fn acquire(&self, wanted: uint) -> uint {
+        do self.free.write |free| {
+            let granted = num::min(*free, wanted);
+            *free -= granted;
+            granted
+        }
+    }
+
+    pub fn release(&self, slots: uint) {
+        self.free.write(|free| *free += slots);
+    }
+
+    // Take up to `wanted` slots, to be given back when the result is dropped,
+    // even if the call using them fails
+    fn take(&self, wanted: uint) -> Slots {
+        Slots { budget: self.clone(), count: self.acquire(wanted) }
+    }
+
+    /**
+     * Like `speculate::spec_timed`, but just run `consumer(producer())` if
+     * there is no slot free for the producer.
+     */
+    pub fn spec<A: Eq + Send + Clone, B>(&self,
+                                         producer: ~fn() -> A,
+                                         predictor: ~fn() -> A,
+                                         consumer:  ~fn(A) -> B) -> (SpecStats, B) {
+        let slots = self.take(1);
+        if slots.count == 0 {
+            let mut stats = SpecStats::new(1);
+            stats.sequential = true;
+            let res = consumer(producer());
+            stats.span.end = precise_time_ns();
+            return (stats, res)
+        }
+
+        let (mut stats, res) = spec_timed(producer, predictor, consumer);
+        stats.slots = slots.count;
+        (stats, res)
+    }
+
+    /**
+     * Like `speculate::specfold_collect`, but with at most one iteration in
+     * flight per slot taken, or sequentially if there are none free.
+     */
+    pub fn specfold<A: Eq + Clone + Send>(&self,
+                                          iters: uint,
+                                          loop_body: &fn() -> ~fn(uint, A) -> A,
+                                          predictor: &fn() -> ~fn(uint) -> A)
+                                          -> (SpecStats, ~[A]) {
+        let slots = self.take(num::max(iters, 1));
+        if slots.count == 0 {
+            return specfold_sequential(iters, loop_body, predictor)
+        }
+
+        let mut results = ~[];
+        let mut stats = specfold_window_on(&TaskExecutor, slots.count, iters,
+                                           || ignore_output(loop_body()), predictor,
+                                           |p, a| *p == *a, |_, res, _| results.push(res));
+        stats.slots = slots.count;
+        (stats, results)
+    }
+}
@@ -0,0 +1,108 @@
+use std::num;
+use extra::arc::Arc;
+
+use super::{Executor, Output, SpecStats, TaskExecutor, specfold_output_on};
+
+/**
+ * The bounds `[lo, hi)` of chunk `i` when `len` elements are split into
+ * `chunks` chunks of (nearly) equal size. The last chunks may be empty.
+ */
+pub fn chunk_bounds(len: uint, chunks: uint, i: uint) -> (uint, uint) {
+    let size = (len + chunks - 1) / chunks; // round up
+    (num::min(i * size, len), num::min((i + 1) * size, len))
+}
+
+/**
+ * Speculatively fold over `input` split into `chunks` chunks, running the
+ * chunks in parallel, and return the output of every chunk in order.
+ *
+ * `step(input, lo, hi, state, output)` processes the chunk `[lo, hi)` starting
+ * from the state carried over from the chunk before, writes whatever it
+ * produces to `output`, and returns the state to carry into the next chunk.
+ * The state need not be a position in `hi` itself: a lexer, for example, can
+ * run past `hi` to finish a token, and return where the next token starts.
+ * `predictor(input, lo)` guesses the state at the start of the chunk starting
+ * at `lo`; for the first chunk, with `lo` 0, it has to give the real initial
+ * state.
+ *
+ * The input is shared between tasks through an `Arc`, so it works for any
+ * container which can be, such as an owned string or vector.
+ */
+pub fn specfold_chunks<I: Container + Freeze + Send, S: Eq + Clone + Send, T: Send>(
+        input: Arc<I>,
+        chunks: uint,
+        step: &fn() -> ~fn(&Arc<I>, uint, uint, S, &mut Output<T>) -> S,
+        predictor: &fn() -> ~fn(&Arc<I>, uint) -> S)
+        -> (SpecStats, ~[T]) {
+    specfold_chunks_on(&TaskExecutor, input, chunks, step, predictor, |p, a| *p == *a)
+}
+
+/**
+ * The general form of `specfold_chunks`, running the chunks on `exec` and
+ * validating guesses with `valid`.
+ */
+pub fn specfold_chunks_on<E: Executor, I: Container + Freeze + Send, S: Clone + Send, T: Send>(
+        exec: &E,
+        input: Arc<I>,
+        chunks: uint,
+        step: &fn() -> ~fn(&Arc<I>, uint, uint, S, &mut Output<T>) -> S,
+        predictor: &fn() -> ~fn(&Arc<I>, uint) -> S,
+        valid: &fn(&S, &S) -> bool)
+        -> (SpecStats, ~[T]) {
+    assert!(chunks > 0);
+    let len = input.get().len();
+
+    let loop_body: &fn() -> ~fn(uint, S, &mut Output<T>) -> S = || {
+        let input = input.clone();
+        let step = step();
+        |i, state, output| {
+            let (lo, hi) = chunk_bounds(len, chunks, i);
+            step(&input, lo, hi, state, output)
+        }
+    };
+    let guess: &fn() -> ~fn(uint) -> S = || {
+        let input = input.clone();
+        let predictor = predictor();
+        |i| {
+            let (lo, _) = chunk_bounds(len, chunks, i);
+            predictor(&input, lo)
+        }
+    };
+
+    let mut output = ~[];
+    let (stats, _) = specfold_output_on(exec, chunks, loop_body, guess, valid,
+                                        |_, items| output.push_all_move(items));
+    (stats, output)
+}
+
+/**
+ * Like `specfold_chunks`, but with `step` and `predictor` as plain functions
+ * which every run shares, as for `specfold_shared`, instead of functions
+ * returning a fresh closure per run.
+ */
+pub fn specfold_chunks_shared<I: Container + Freeze + Send, S: Eq + Clone + Send, T: Send>(
+        input: Arc<I>,
+        chunks: uint,
+        step: extern fn(&Arc<I>, uint, uint, S, &mut Output<T>) -> S,
+        predictor: extern fn(&Arc<I>, uint) -> S)
+        -> (SpecStats, ~[T]) {
+    specfold_chunks_shared_on(&TaskExecutor, input, chunks, step, predictor, |p, a| *p == *a)
+}
+
+/**
+ * The general form of `specfold_chunks_shared`.
+ */
+pub fn specfold_chunks_shared_on<E: Executor, I: Container + Freeze + Send, S: Clone + Send,
+                                 T: Send>(
+        exec: &E,
+        input: Arc<I>,
+        chunks: uint,
+        step: extern fn(&Arc<I>, uint, uint, S, &mut Output<T>) -> S,
+        predictor: extern fn(&Arc<I>, uint) -> S,
+        valid: &fn(&S, &S) -> bool)
+        -> (SpecStats, ~[T]) {
+    specfold_chunks_on(exec, input, chunks,
+                       || |input, lo, hi, state, output| step(input, lo, hi, state, output),
+                       || |input, lo| predictor(input, lo),
+                       valid)
+}
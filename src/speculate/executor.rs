@@ -0,0 +1,137 @@
+use std::task;
+use std::cell::Cell;
+use std::comm::{stream, SharedChan, SharedPort};
+use extra::container::Deque;
+use extra::ringbuf::RingBuf;
+
+/**
+ * Something which can run the speculative work started by `spec_on` and
+ * `specfold_on`.
+ */
+pub trait Executor {
+    /**
+     * Run `job` at some point, usually in another task. Must not wait for
+     * `job` to finish.
+     */
+    fn execute(&self, job: ~fn());
+
+    /**
+     * Called from the calling task when it is about to block until a job has
+     * finished. Executors which do not run jobs on their own run some here.
+     */
+    fn wait(&self) {}
+}
+
+/**
+ * Spawns a new task for every job. This is what `spec` and `specfold` use.
+ */
+pub struct TaskExecutor;
+
+impl Executor for TaskExecutor {
+    fn execute(&self, job: ~fn()) {
+        task::spawn(job);
+    }
+}
+
+/**
+ * Runs jobs one at a time in the calling task, oldest first, and only when
+ * asked to: one each time the caller waits, or all of them with `run`.
+ *
+ * Nothing runs in parallel, so it is mostly useful for tests. The futures
+ * returned by `spec_future_on` are only resolved once the executor has run
+ * the jobs behind them, so call `run` before forcing them.
+ */
+pub struct LocalExecutor {
+    priv jobs: Cell<RingBuf<~fn()>>
+}
+
+impl LocalExecutor {
+    pub fn new() -> LocalExecutor {
+        LocalExecutor { jobs: Cell::new(RingBuf::new()) }
+    }
+
+    /**
+     * Run the oldest pending job, returning `false` if there was none.
+     */
+    pub fn run_one(&self) -> bool {
+        let mut jobs = self.jobs.take();
+        let job = jobs.pop_front();
+        // The job may start jobs of its own
+        self.jobs.put_back(jobs);
+        match job {
+            Some(job) => { job(); true }
+            None => false
+        }
+    }
+
+    /**
+     * Run jobs until there are none left, including any started along the way.
+     */
+    pub fn run(&self) {
+        while self.run_one() {}
+    }
+}
+
+impl Executor for LocalExecutor {
+    fn execute(&self, job: ~fn()) {
+        let mut jobs = self.jobs.take();
+        jobs.push_back(job);
+        self.jobs.put_back(jobs);
+    }
+
+    fn wait(&self) {
+        self.run_one();
+    }
+}
+
+/**
+ * A fixed number of worker tasks running jobs from a shared queue.
+ *
+ * Clones share the same workers, so one pool can be handed to many concurrent
+ * speculative calls without oversubscribing the machine. The workers exit once
+ * every clone has been dropped and the queue is empty.
+ *
+ * A job which is itself waiting for other jobs on the same pool (such as a
+ * nested `specfold_on`) holds on to its worker while it waits, so the pool
+ * needs to be big enough for every level of nesting.
+ */
+#[deriving(Clone)]
+pub struct WorkerPool {
+    priv jobs: SharedChan<~fn()>
+}
+
+impl WorkerPool {
+    pub fn new(size: uint) -> WorkerPool {
+        assert!(size > 0);
+        let (port, chan) = stream();
+        let port = SharedPort::new(port);
+
+        for _ in range(0, size) {
+            let port = port.clone();
+            do task::spawn_unlinked {
+                // A failing job only takes down the task it ran in, so start a
+                // new one to keep the pool at its full size.
+                loop {
+                    let worker_port = port.clone();
+                    let res = do task::try {
+                        loop {
+                            match worker_port.try_recv() {
+                                Some(job) => job(),
+                                None => break
+                            }
+                        }
+                    };
+                    if res.is_ok() { break }
+                }
+            }
+        }
+
+        WorkerPool { jobs: SharedChan::new(chan) }
+    }
+}
+
+impl Executor for WorkerPool {
+    fn execute(&self, job: ~fn()) {
+        self.jobs.send(job);
+    }
+}
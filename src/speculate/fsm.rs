@@ -0,0 +1,200 @@
+/*!
+ * Running a finite-state machine over a large buffer in parallel.
+ *
+ * The buffer is split into chunks, and each chunk is run from a guess of the
+ * state the machine will be in when it gets there. `fsm_spec` validates the
+ * guesses exactly like `specfold`. For machines with only a few states,
+ * `fsm_enumerate` instead runs every chunk from every state, so nothing ever
+ * has to be re-run.
+ */
+
+use std::{num, vec};
+use std::cell::Cell;
+use std::comm::{stream, SharedChan};
+use extra::arc::Arc;
+use extra::time::precise_time_ns;
+
+use super::{Executor, Output, Span, SpecStats, TaskExecutor, chunk_bounds, specfold_chunks_on};
+
+/**
+ * A deterministic finite-state transducer over bytes. Every transition goes
+ * to a new state and may emit a value.
+ */
+pub struct Fsm<T> {
+    priv states: uint,
+    // The transitions from each state, indexed by `state * 256 + byte`
+    priv table: ~[(uint, Option<T>)]
+}
+
+impl<T: Clone> Fsm<T> {
+    /**
+     * A machine with `states` states, where every transition goes to `to`
+     * and emits nothing until set otherwise.
+     */
+    pub fn new(states: uint, to: uint) -> Fsm<T> {
+        assert!(to < states);
+        Fsm { states: states, table: vec::from_elem(states * 256, (to, None)) }
+    }
+
+    pub fn states(&self) -> uint { self.states }
+
+    /**
+     * Make `byte` take state `from` to state `to`, emitting `emit`.
+     */
+    pub fn set(&mut self, from: uint, byte: u8, to: uint, emit: Option<T>) {
+        assert!(from < self.states && to < self.states);
+        self.table[from * 256 + byte as uint] = (to, emit);
+    }
+
+    /**
+     * Follow the transition from `state` on `byte`, pushing anything it emits
+     * to `output`.
+     */
+    pub fn step(&self, state: uint, byte: u8, output: &mut ~[T]) -> uint {
+        match self.table[state * 256 + byte as uint] {
+            (to, ref emit) => {
+                match *emit {
+                    Some(ref val) => output.push(val.clone()),
+                    None => ()
+                }
+                to
+            }
+        }
+    }
+
+    /**
+     * Run the machine over `input` from `state`, returning the state it ends
+     * up in.
+     */
+    pub fn run(&self, state: uint, input: &[u8], output: &mut ~[T]) -> uint {
+        let mut state = state;
+        for byte in input.iter() {
+            state = self.step(state, *byte, output);
+        }
+        state
+    }
+
+    /**
+     * Guess the state at `pos` by running the machine from `from` over the
+     * `lookback` bytes before it. For machines which soon forget where they
+     * started, like most lexers, this is usually right.
+     */
+    pub fn guess_state(&self, input: &[u8], pos: uint, lookback: uint, from: uint) -> uint {
+        let mut ignored = ~[];
+        self.run(from, input.slice(pos - num::min(pos, lookback), pos), &mut ignored)
+    }
+}
+
+/**
+ * Run `fsm` over `input` from state `start`, split into `chunks` chunks which
+ * are run in parallel. Returns the final state and what each chunk emitted.
+ *
+ * `predictor()(input, lo)` guesses the state at the start of the chunk
+ * starting at `lo`; it is not asked about the first chunk. A chunk whose
+ * guess turns out to be wrong is run again from the real state, as in
+ * `specfold`.
+ */
+pub fn fsm_spec<T: Clone + Freeze + Send>(fsm: Arc<Fsm<T>>,
+                                          input: Arc<~[u8]>,
+                                          start: uint,
+                                          chunks: uint,
+                                          predictor: &fn() -> ~fn(&[u8], uint) -> uint)
+                                          -> (SpecStats, uint, ~[~[T]]) {
+    fsm_spec_on(&TaskExecutor, fsm, input, start, chunks, predictor)
+}
+
+/**
+ * Like `fsm_spec`, but run the chunks on `exec`.
+ */
+pub fn fsm_spec_on<E: Executor, T: Clone + Freeze + Send>(
+        exec: &E,
+        fsm: Arc<Fsm<T>>,
+        input: Arc<~[u8]>,
+        start: uint,
+        chunks: uint,
+        predictor: &fn() -> ~fn(&[u8], uint) -> uint)
+        -> (SpecStats, uint, ~[~[T]]) {
+    let step: &fn() -> ~fn(&Arc<~[u8]>, uint, uint, uint, &mut Output<(uint, ~[T])>) -> uint = || {
+        let fsm = fsm.clone();
+        |input, lo, hi, state, output| {
+            let mut emitted = ~[];
+            let end = fsm.get().run(state, input.get().slice(lo, hi), &mut emitted);
+            output.push((end, emitted));
+            end
+        }
+    };
+    let guess: &fn() -> ~fn(&Arc<~[u8]>, uint) -> uint = || {
+        let p = predictor();
+        |input, lo| if lo == 0 { start } else { p(input.get().slice_from(0), lo) }
+    };
+
+    let (stats, results) = specfold_chunks_on(exec, input, chunks, step, guess, |p, a| *p == *a);
+    let end = match *results.last() { (end, _) => end };
+    (stats, end, results.move_iter().map(|(_, emitted)| emitted).collect())
+}
+
+/**
+ * Like `fsm_spec`, but with no predictor: every chunk is run from every state
+ * of `fsm` at once, and the runs are then chained together from `start`.
+ *
+ * This does `fsm.states()` times the work of a sequential run, but never has
+ * to wait for a re-execution, so it is best for machines with few states.
+ */
+pub fn fsm_enumerate<T: Clone + Freeze + Send>(fsm: Arc<Fsm<T>>,
+                                               input: Arc<~[u8]>,
+                                               start: uint,
+                                               chunks: uint)
+                                               -> (SpecStats, uint, ~[~[T]]) {
+    fsm_enumerate_on(&TaskExecutor, fsm, input, start, chunks)
+}
+
+/**
+ * Like `fsm_enumerate`, but run the chunks on `exec`.
+ */
+pub fn fsm_enumerate_on<E: Executor, T: Clone + Freeze + Send>(exec: &E,
+                                                               fsm: Arc<Fsm<T>>,
+                                                               input: Arc<~[u8]>,
+                                                               start: uint,
+                                                               chunks: uint)
+                                                               -> (SpecStats, uint, ~[~[T]]) {
+    assert!(chunks > 0);
+    let mut stats = SpecStats::new(chunks);
+    let len = input.get().len();
+    let (port, chan) = stream();
+    let chan = SharedChan::new(chan);
+
+    for i in range(0, chunks) {
+        let args = Cell::new((fsm.clone(), input.clone(), chan.clone()));
+        do exec.execute {
+            let (fsm, input, chan) = args.take();
+            let (lo, hi) = chunk_bounds(len, chunks, i);
+            let begin = precise_time_ns();
+            // Where each start state ends up, and what it emits on the way
+            let runs = do vec::from_fn(fsm.get().states()) |state| {
+                let mut emitted = ~[];
+                let end = fsm.get().run(state, input.get().slice(lo, hi), &mut emitted);
+                (end, emitted)
+            };
+            chan.send((i, runs, Span { start: begin, end: precise_time_ns() }));
+        }
+    }
+
+    let mut runs = vec::from_fn(chunks, |_| None);
+    for _ in range(0, chunks) {
+        if !port.peek() { exec.wait() }
+        let (i, chunk_runs, span) = port.recv();
+        stats.times[i].body = span;
+        runs[i] = Some(chunk_runs);
+    }
+
+    let mut state = start;
+    let mut outputs = vec::with_capacity(chunks);
+    for chunk_runs in runs.move_iter() {
+        let mut chunk_runs = chunk_runs.unwrap();
+        let (end, emitted) = chunk_runs.swap_remove(state);
+        outputs.push(emitted);
+        state = end;
+    }
+    stats.span.end = precise_time_ns();
+    (stats, state, outputs)
+}
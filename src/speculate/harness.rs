@@ -0,0 +1,132 @@
+/*!
+ * Running speculative code reproducibly in tests.
+ *
+ * A `TestExecutor` never runs anything in parallel: jobs are queued, and one
+ * of them is run, to completion, each time the caller has to wait for one.
+ * Which one is decided by a list of choices, so a test can replay a given
+ * completion order, or go through all of them with `for_each_schedule`.
+ */
+
+use std::{num, vec};
+use std::cell::Cell;
+
+use super::{Executor, Output, SpecStats, fold_on};
+
+pub struct TestExecutor {
+    priv pending: Cell<~[~fn()]>,
+    // The index into the pending jobs of the one to run each time a job is
+    // picked. Past the end, the oldest job is run.
+    priv choices: ~[uint],
+    // How many jobs there were to pick from each time
+    priv branching: Cell<~[uint]>
+}
+
+impl TestExecutor {
+    /**
+     * An executor which runs jobs in the order they were started.
+     */
+    pub fn new() -> TestExecutor {
+        TestExecutor::with_choices(~[])
+    }
+
+    /**
+     * An executor which runs job `choices[k]` (counting from the oldest
+     * pending one) the `k`th time a job has to be picked.
+     */
+    pub fn with_choices(choices: ~[uint]) -> TestExecutor {
+        TestExecutor { pending: Cell::new(~[]),
+                       choices: choices,
+                       branching: Cell::new(~[]) }
+    }
+
+    /**
+     * The number of pending jobs each time a job was picked so far.
+     */
+    pub fn branching(&self) -> ~[uint] {
+        let branching = self.branching.take();
+        let res = branching.clone();
+        self.branching.put_back(branching);
+        res
+    }
+}
+
+impl Executor for TestExecutor {
+    fn execute(&self, job: ~fn()) {
+        let mut pending = self.pending.take();
+        pending.push(job);
+        self.pending.put_back(pending);
+    }
+
+    fn wait(&self) {
+        let mut pending = self.pending.take();
+        if pending.is_empty() {
+            self.pending.put_back(pending);
+            return
+        }
+
+        let mut branching = self.branching.take();
+        let point = branching.len();
+        let choice = if point < self.choices.len() {
+            self.choices[point] % pending.len()
+        } else {
+            0
+        };
+        branching.push(pending.len());
+        self.branching.put_back(branching);
+
+        let job = pending.remove(choice);
+        self.pending.put_back(pending);
+        job();
+    }
+}
+
+/**
+ * Call `f` once for every order in which the jobs it starts on the executor it
+ * is given can complete.
+ *
+ * `f` has to start the same jobs whenever they complete in the same order,
+ * which holds for `spec_on` and the `specfold` family as long as the
+ * predictor and loop body are deterministic. The number of orders grows very
+ * quickly with the number of jobs, so keep loops short.
+ */
+pub fn for_each_schedule(f: &fn(&TestExecutor)) {
+    let mut choices = ~[];
+    loop {
+        let exec = TestExecutor::with_choices(choices.clone());
+        f(&exec);
+
+        // Take the next choice at the last point which has one left
+        let branching = exec.branching();
+        while choices.len() < branching.len() { choices.push(0); }
+        let mut k = branching.len();
+        while k > 0 && choices[k - 1] + 1 >= branching[k - 1] { k -= 1; }
+        if k == 0 { break }
+        choices.truncate(k);
+        choices[k - 1] += 1;
+    }
+}
+
+/**
+ * Like `specfold_output` run on `exec`, but the speculative run of every
+ * iteration in `forced` is treated as a misprediction, even if the predictor
+ * guessed right. The first iteration is never validated, so forcing it has no
+ * effect.
+ */
+pub fn specfold_forced<E: Executor, A: Clone + Send, T: Send>(
+        exec: &E,
+        iters: uint,
+        loop_body: &fn() -> ~fn(uint, A, &mut Output<T>) -> A,
+        predictor: &fn() -> ~fn(uint) -> A,
+        valid: &fn(&A, &A) -> bool,
+        forced: &[uint])
+        -> (SpecStats, ~[A], ~[T]) {
+    let mut results = vec::with_capacity(iters);
+    let mut outputs = ~[];
+    let collect: &fn(uint, A, ~[T]) = |_, res, items| {
+        results.push(res);
+        outputs.push_all_move(items);
+    };
+    let stats = fold_on(exec, num::max(iters, 1), iters, loop_body, predictor, valid, forced,
+                        |_| false, collect);
+    (stats, results, outputs)
+}
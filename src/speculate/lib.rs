@@ -2,11 +2,58 @@
 
 extern mod extra;
 
+use std::{num, task, uint, util, vec};
+use std::cell::Cell;
+use std::comm::{oneshot, stream, SharedChan};
+use extra::arc::{Arc, RWArc};
+use extra::container::Deque;
 use extra::future::Future;
+use extra::ringbuf::RingBuf;
+use extra::time::precise_time_ns;
 
-pub struct SpecStats {
-    iters: uint,
-    mispredictions: ~[bool]
+pub use adaptive::*;
+pub use budget::*;
+pub use chunks::*;
+pub use executor::*;
+pub use fsm::*;
+pub use memo::*;
+pub use pipeline::*;
+pub use stats::*;
+pub use timeline::*;
+
+pub mod adaptive;
+pub mod budget;
+pub mod chunks;
+pub mod executor;
+pub mod fsm;
+pub mod harness;
+pub mod memo;
+pub mod pipeline;
+pub mod predictors;
+pub mod stats;
+pub mod timeline;
+
+/**
+ * A flag which a cancellable consumer can poll to find out that the value it
+ * was given has turned out to be a misprediction.
+ */
+#[deriving(Clone)]
+pub struct CancelToken {
+    priv cancelled: RWArc<bool>
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken { cancelled: RWArc::new(false) }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.write(|c| *c = true);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.read(|c| *c)
+    }
 }
 
 /**
@@ -15,17 +62,320 @@ pub struct SpecStats {
 pub fn spec<A: Eq + Send + Clone, B>(producer: ~fn() -> A,
                                      predictor: ~fn() -> A,
                                      consumer:  ~fn(A) -> B) -> B {
+    spec_by(producer, predictor, consumer, |p, a| *p == *a)
+}
+
+/**
+ * Like `spec`, but decide whether the guess was good enough with `valid`
+ * instead of `==`.
+ *
+ * `valid` is called as `valid(&predicted, &actual)`. If it returns `true`, the
+ * result of running consumer on the predicted value is kept, even if the two
+ * values are not identical.
+ */
+pub fn spec_by<A: Send + Clone, B>(producer: ~fn() -> A,
+                                   predictor: ~fn() -> A,
+                                   consumer:  ~fn(A) -> B,
+                                   valid: &fn(&A, &A) -> bool) -> B {
+    spec_on(&TaskExecutor, producer, predictor, consumer, valid)
+}
+
+/**
+ * Like `spec_by`, but run the producer on `exec` instead of in a new task.
+ */
+pub fn spec_on<E: Executor, A: Send + Clone, B>(exec: &E,
+                                                producer: ~fn() -> A,
+                                                predictor: ~fn() -> A,
+                                                consumer:  ~fn(A) -> B,
+                                                valid: &fn(&A, &A) -> bool) -> B {
+    run_spec(exec, producer, predictor, consumer, valid, None)
+}
+
+/**
+ * Like `spec`, but also return when each part of the call happened.
+ *
+ * The stats describe a single iteration: `times[0].predictor` is the
+ * predictor, `times[0].body` the consumer run on the guess and
+ * `times[0].reexecs` the consumer run on the real value, if there was one.
+ * `producer` is set to when the producer ran.
+ */
+pub fn spec_timed<A: Eq + Send + Clone, B>(producer: ~fn() -> A,
+                                           predictor: ~fn() -> A,
+                                           consumer:  ~fn(A) -> B) -> (SpecStats, B) {
+    spec_timed_on(&TaskExecutor, producer, predictor, consumer, |p, a| *p == *a)
+}
+
+/**
+ * Like `spec_on`, but also return when each part of the call happened, as
+ * for `spec_timed`.
+ */
+pub fn spec_timed_on<E: Executor, A: Send + Clone, B>(exec: &E,
+                                                      producer: ~fn() -> A,
+                                                      predictor: ~fn() -> A,
+                                                      consumer:  ~fn(A) -> B,
+                                                      valid: &fn(&A, &A) -> bool)
+                                                      -> (SpecStats, B) {
+    let mut stats = SpecStats::new(1);
+    let result = run_spec(exec, producer, predictor, consumer, valid, Some(&mut stats));
+    stats.span.end = precise_time_ns();
+    (stats, result)
+}
+
+// The engine behind `spec_on` and `spec_timed_on`. Only with `stats` is the
+// clock read, and when each part ran recorded there.
+fn run_spec<E: Executor, A: Send + Clone, B>(exec: &E,
+                                             producer: ~fn() -> A,
+                                             predictor: ~fn() -> A,
+                                             consumer:  ~fn(A) -> B,
+                                             valid: &fn(&A, &A) -> bool,
+                                             stats: Option<&mut SpecStats>) -> B {
+    let timed = stats.is_some();
+    let now = || if timed { precise_time_ns() } else { 0 };
+
+    let (port, chan) = oneshot();
+    let args = Cell::new((producer, chan));
+    do exec.execute {
+        let (producer, chan) = args.take();
+        let start = if timed { precise_time_ns() } else { 0 };
+        let res = producer();
+        let end = if timed { precise_time_ns() } else { 0 };
+        chan.send((res, Span { start: start, end: end }));
+    }
+    let start = now();
+    let prediction = predictor();
+    let predicted = now();
+    let speculative_result = consumer(prediction.clone());
+    let consumed = now();
+    exec.wait();
+    let (real_value, produced) = port.recv();
+
+    let mispredicted = !valid(&prediction, &real_value);
+    let (result, reexec) = if mispredicted {
+        let start = now();
+        let res = consumer(real_value);
+        (res, Span { start: start, end: now() })
+    } else {
+        (speculative_result, Span { start: 0, end: 0 })
+    };
+
+    match stats {
+        Some(stats) => {
+            stats.times[0].predictor = Span { start: start, end: predicted };
+            stats.times[0].body = Span { start: predicted, end: consumed };
+            stats.producer = Some(produced);
+            if mispredicted {
+                stats.mispredictions[0] = true;
+                stats.times[0].committed = 1;
+                stats.times[0].reexecs.push(reexec);
+            }
+        }
+        None => ()
+    }
+    result
+}
 
+/**
+ * Like `spec`, for a producer and consumer which can fail with an `Err`.
+ *
+ * An `Err` from the producer, or from the consumer run on a correct guess or
+ * on the real value, is returned as is, and `error_at` is set. An `Err` from
+ * the consumer run on a guess which is thrown away, because it was wrong or
+ * because the producer failed, is counted in `errors`.
+ */
+pub fn try_spec<A: Eq + Send + Clone, B, E: Send>(producer: ~fn() -> Result<A, E>,
+                                                   predictor: ~fn() -> A,
+                                                   consumer:  ~fn(A) -> Result<B, E>)
+                                                   -> (SpecStats, Result<B, E>) {
+    let mut stats = SpecStats::new(1);
     let producer_result = Future::spawn(producer);
     let prediction = predictor();
     let speculative_result = consumer(prediction.clone());
+
+    let result = match producer_result.unwrap() {
+        Err(e) => {
+            if speculative_result.is_err() {
+                stats.errors += 1;
+            }
+            Err(e)
+        }
+        Ok(real_value) => {
+            if prediction == real_value {
+                speculative_result
+            } else {
+                stats.mispredictions[0] = true;
+                if speculative_result.is_err() {
+                    stats.errors += 1;
+                }
+                consumer(real_value)
+            }
+        }
+    };
+    if result.is_err() {
+        stats.error_at = Some(0);
+    }
+    stats.span.end = precise_time_ns();
+    (stats, result)
+}
+
+/**
+ * Like `spec`, but only require the guess to agree with the real value on
+ * `key`.
+ */
+pub fn spec_by_key<A: Send + Clone, B, K: Eq>(producer: ~fn() -> A,
+                                              predictor: ~fn() -> A,
+                                              consumer:  ~fn(A) -> B,
+                                              key: &fn(&A) -> K) -> B {
+    spec_by(producer, predictor, consumer, |p, a| key(p) == key(a))
+}
+
+/**
+ * Like `spec`, but a failure of the consumer while it is running on a wrong
+ * guess is treated like any other misprediction.
+ *
+ * The speculative consumer is run in its own task, so a guess which breaks an
+ * invariant the real value would not (say, an index out of range) does not
+ * take down the call. A failure is only propagated if the guess was correct,
+ * or from the re-execution on the real value.
+ */
+pub fn spec_isolated<A: Eq + Send + Clone, B: Send>(producer: ~fn() -> A,
+                                                    predictor: ~fn() -> A,
+                                                    consumer: &fn() -> ~fn(A) -> B) -> B {
+    let producer_result = Future::spawn(producer);
+    let prediction = predictor();
+    let speculative_consumer = consumer();
+    let guess = prediction.clone();
+    let speculative_result = isolate(|| speculative_consumer(guess.clone()));
     let real_value = producer_result.unwrap();
 
     if real_value == prediction {
+        match speculative_result {
+            Some(res) => res,
+            None => fail!("consumer failed on a correct prediction")
+        }
+    } else {
+        consumer()(real_value)
+    }
+}
+
+/**
+ * Speculatively execute consumer, abandoning the speculative run as soon as the
+ * guess is known to be wrong.
+ *
+ * The producer checks its result against the prediction as soon as it
+ * finishes, and cancels the token held by the speculative consumer if they do
+ * not match. A consumer which polls `is_cancelled` can then return early (with
+ * any value; it will be discarded), so the re-execution with the real value
+ * starts without waiting for a full wasted run.
+ */
+pub fn spec_cancellable<A: Eq + Send + Clone, B>(producer: ~fn() -> A,
+                                                 predictor: ~fn() -> A,
+                                                 consumer:  ~fn(A, CancelToken) -> B) -> B {
+    let token = CancelToken::new();
+    // Start the producer before the predictor, and hand it the guess to check
+    // once there is one
+    let (guess_port, guess_chan) = oneshot();
+    let args = (producer, guess_port, token.clone());
+    let producer_result = do Future::spawn_with(args) |(p, guess, tok)| {
+        let real_value = p();
+        let correct = real_value == guess.recv();
+        if !correct { tok.cancel(); }
+        (real_value, correct)
+    };
+
+    let prediction = predictor();
+    guess_chan.send(prediction.clone());
+    let speculative_result = consumer(prediction, token);
+    let (real_value, correct) = producer_result.unwrap();
+
+    if correct {
         speculative_result
     } else {
-        consumer(real_value)
+        consumer(real_value, CancelToken::new())
+    }
+}
+
+/**
+ * Speculatively execute consumer on several guesses at once.
+ *
+ * Each guess returned by `predictor` is handed to its own consumer task, and
+ * the producer runs alongside them in a task of its own. The run whose guess equals the
+ * producer's value is returned, and its index is recorded in `winner`. If no
+ * guess matches, the consumer is re-run with the real value.
+ */
+pub fn spec_any<A: Eq + Send + Clone, B: Send>(producer: ~fn() -> A,
+                                               predictor: ~fn() -> ~[A],
+                                               consumer: &fn() -> ~fn(A) -> B) -> (SpecStats, B) {
+    let producer_result = Future::spawn(producer);
+    let guesses = predictor();
+    let mut runs: ~[Future<B>] = vec::with_capacity(guesses.len());
+    for guess in guesses.iter() {
+        let run = do Future::spawn_with((guess.clone(), consumer())) |(g, c)| { c(g) };
+        runs.push(run);
+    }
+
+    let real_value = producer_result.unwrap();
+    let mut stats = SpecStats::new(guesses.len());
+    for (i, guess) in guesses.iter().enumerate() {
+        stats.mispredictions[i] = *guess != real_value;
     }
+    stats.winner = guesses.iter().position(|g| *g == real_value);
+
+    let result = match stats.winner {
+        Some(i) => runs.swap_remove(i).unwrap(),
+        None => consumer()(real_value)
+    };
+    (stats, result)
+}
+
+/**
+ * Like `spec`, but without blocking the calling task.
+ *
+ * The producer and the consumer's results are both futures. The producer is
+ * forced in its own task, so even a lazily evaluated future runs alongside the
+ * speculative consumer. The returned future resolves to the validated result.
+ */
+pub fn spec_future<A: Eq + Send + Clone, B: Send>(producer: Future<A>,
+                                                  predictor: ~fn() -> A,
+                                                  consumer: ~fn(A) -> Future<B>) -> Future<B> {
+    spec_future_on(&TaskExecutor, producer, predictor, consumer)
+}
+
+/**
+ * Like `spec_future`, but force the producer in one job on `exec`, and run
+ * the predictor, the consumer and the validation in another.
+ *
+ * The returned future resolves once `exec` has run both jobs. With an
+ * executor which only runs jobs when asked to, such as `LocalExecutor`, have
+ * it run them before forcing the future.
+ */
+pub fn spec_future_on<E: Executor, A: Eq + Send + Clone, B: Send>(
+        exec: &E,
+        producer: Future<A>,
+        predictor: ~fn() -> A,
+        consumer: ~fn(A) -> Future<B>)
+        -> Future<B> {
+    let (real_port, real_chan) = oneshot();
+    let args = Cell::new((producer, real_chan));
+    do exec.execute {
+        let (producer, real_chan) = args.take();
+        real_chan.send(producer.unwrap());
+    }
+
+    let (port, chan) = oneshot();
+    let args = Cell::new((predictor, consumer, real_port, chan));
+    do exec.execute {
+        let (predictor, consumer, real_port, chan) = args.take();
+        let prediction = predictor();
+        let speculative_result = consumer(prediction.clone()).unwrap();
+        let real_value = real_port.recv();
+
+        if real_value == prediction {
+            chan.send(speculative_result);
+        } else {
+            chan.send(consumer(real_value).unwrap());
+        }
+    }
+    Future::from_port(port)
 }
 
 /**
@@ -37,33 +387,913 @@ pub fn spec<A: Eq + Send + Clone, B>(producer: ~fn() -> A,
 pub fn specfold<A: Eq + Clone + Send>(iters: uint,
                                       loop_body: &fn() -> ~fn(uint, A) -> A,
                                       predictor: &fn() -> ~fn(uint) -> A) -> SpecStats {
+    let (stats, _) = specfold_collect(iters, loop_body, predictor);
+    stats
+}
+
+/**
+ * Like `specfold`, but also return the committed result of every iteration.
+ *
+ * The `i`-th element of the returned vector is the value produced by whichever
+ * run of iteration `i` was validated, so callers do not need to smuggle results
+ * out through a channel.
+ */
+pub fn specfold_collect<A: Eq + Clone + Send>(iters: uint,
+                                              loop_body: &fn() -> ~fn(uint, A) -> A,
+                                              predictor: &fn() -> ~fn(uint) -> A)
+                                              -> (SpecStats, ~[A]) {
+    specfold_collect_by(iters, loop_body, predictor, |p, a| *p == *a)
+}
+
+/**
+ * Like `specfold_collect`, but with one loop body and one predictor which
+ * every run shares, instead of functions returning a fresh closure per run.
+ *
+ * Both are plain functions, so they can be sent to any task, and get the
+ * shared data they need from `env`, which is handed out by reference. This
+ * saves callers from building a closure, and a copy of whatever it captures,
+ * for every run.
+ */
+pub fn specfold_shared<Env: Freeze + Send, A: Eq + Clone + Send>(
+        iters: uint,
+        env: Arc<Env>,
+        loop_body: extern fn(&Env, uint, A) -> A,
+        predictor: extern fn(&Env, uint) -> A)
+        -> (SpecStats, ~[A]) {
+    specfold_shared_on(&TaskExecutor, iters, env, loop_body, predictor, |p, a| *p == *a)
+}
+
+/**
+ * The general form of `specfold_shared`.
+ */
+pub fn specfold_shared_on<E: Executor, Env: Freeze + Send, A: Clone + Send>(
+        exec: &E,
+        iters: uint,
+        env: Arc<Env>,
+        loop_body: extern fn(&Env, uint, A) -> A,
+        predictor: extern fn(&Env, uint) -> A,
+        valid: &fn(&A, &A) -> bool)
+        -> (SpecStats, ~[A]) {
+    let body: &fn() -> ~fn(uint, A) -> A = || {
+        let env = env.clone();
+        |i, val| loop_body(env.get(), i, val)
+    };
+    let guess: &fn() -> ~fn(uint) -> A = || {
+        let env = env.clone();
+        |i| predictor(env.get(), i)
+    };
+    specfold_on(exec, iters, body, guess, valid)
+}
+
+/**
+ * Run the same loop as `specfold_collect` one iteration after another in the
+ * calling task, without speculating. Only the initial value is taken from
+ * `predictor`. The returned stats have `sequential` set.
+ */
+pub fn specfold_sequential<A: Clone>(iters: uint,
+                                     loop_body: &fn() -> ~fn(uint, A) -> A,
+                                     predictor: &fn() -> ~fn(uint) -> A)
+                                     -> (SpecStats, ~[A]) {
+    let mut stats = SpecStats::new(iters);
+    stats.sequential = true;
+    let mut results = vec::with_capacity(iters);
+    if iters > 0 {
+        let body = loop_body();
+        let predicted = Span::now();
+        let mut val = predictor()(0);
+        stats.times[0].predictor = Span { start: predicted.start, end: precise_time_ns() };
+        for i in range(0, iters) {
+            let mut span = Span::now();
+            val = body(i, val);
+            span.end = precise_time_ns();
+            stats.times[i].body = span;
+            results.push(val.clone());
+        }
+    }
+    stats.span.end = precise_time_ns();
+    (stats, results)
+}
+
+/**
+ * Like `specfold`, but check each prediction with `valid` instead of `==`.
+ *
+ * `valid` is called as `valid(&predicted, &actual)`, where `actual` is the
+ * committed result of the previous iteration.
+ */
+pub fn specfold_by<A: Clone + Send>(iters: uint,
+                                    loop_body: &fn() -> ~fn(uint, A) -> A,
+                                    predictor: &fn() -> ~fn(uint) -> A,
+                                    valid: &fn(&A, &A) -> bool) -> SpecStats {
+    let (stats, _) = specfold_collect_by(iters, loop_body, predictor, valid);
+    stats
+}
+
+/**
+ * Like `specfold_collect`, but check each prediction with `valid` instead of
+ * `==`.
+ */
+pub fn specfold_collect_by<A: Clone + Send>(iters: uint,
+                                            loop_body: &fn() -> ~fn(uint, A) -> A,
+                                            predictor: &fn() -> ~fn(uint) -> A,
+                                            valid: &fn(&A, &A) -> bool)
+                                            -> (SpecStats, ~[A]) {
+    specfold_on(&TaskExecutor, iters, loop_body, predictor, valid)
+}
+
+/**
+ * Like `specfold_collect_by`, but run the iterations on `exec` instead of in a
+ * new task each.
+ */
+pub fn specfold_on<E: Executor, A: Clone + Send>(exec: &E,
+                                                 iters: uint,
+                                                 loop_body: &fn() -> ~fn(uint, A) -> A,
+                                                 predictor: &fn() -> ~fn(uint) -> A,
+                                                 valid: &fn(&A, &A) -> bool)
+                                                 -> (SpecStats, ~[A]) {
+    specfold_output_on(exec, iters, || ignore_output(loop_body()), predictor, valid, |_, _| ())
+}
+
+/**
+ * Like `specfold_collect`, but also let each iteration write to an `Output`
+ * buffer, whose contents are returned in iteration order.
+ *
+ * Only the output of the committed run of each iteration is kept, so a loop
+ * body does not need to undo anything it wrote on a mispredicted run.
+ */
+pub fn specfold_output<A: Eq + Clone + Send, T: Send>(
+        iters: uint,
+        loop_body: &fn() -> ~fn(uint, A, &mut Output<T>) -> A,
+        predictor: &fn() -> ~fn(uint) -> A)
+        -> (SpecStats, ~[A], ~[T]) {
+    let mut output = ~[];
+    let (stats, results) = specfold_output_on(&TaskExecutor, iters, loop_body, predictor,
+                                              |p, a| *p == *a,
+                                              |_, items| output.push_all_move(items));
+    (stats, results, output)
+}
+
+/**
+ * The general form of `specfold_output`.
+ *
+ * `publish` is called from the calling task with the index and output of each
+ * iteration, in order, as soon as that iteration has been committed.
+ */
+pub fn specfold_output_on<E: Executor, A: Clone + Send, T: Send>(
+        exec: &E,
+        iters: uint,
+        loop_body: &fn() -> ~fn(uint, A, &mut Output<T>) -> A,
+        predictor: &fn() -> ~fn(uint) -> A,
+        valid: &fn(&A, &A) -> bool,
+        publish: &fn(uint, ~[T]))
+        -> (SpecStats, ~[A]) {
+    let mut results = vec::with_capacity(iters);
+    let collect: &fn(uint, A, ~[T]) = |i, res, items| {
+        results.push(res);
+        publish(i, items);
+    };
+    let stats = specfold_window_on(exec, num::max(iters, 1), iters, loop_body, predictor, valid,
+                                   collect);
+    (stats, results)
+}
+
+/**
+ * Like `specfold_output`, but recover from a misprediction by repairing the
+ * speculative run rather than throwing it away.
+ *
+ * The loop body reports its state through `Repair::checkpoint`. A
+ * re-execution on the real input only runs until it reaches a state the
+ * speculative run also checkpointed; from there on the two would do the same,
+ * so the rest of the speculative run's output and its result are used. This
+ * pays off for loops which quickly get back in step after starting from a
+ * wrong state, such as a lexer which soon reaches the same token boundary.
+ */
+pub fn specfold_repair<A: Eq + Clone + Send, T: Send>(
+        iters: uint,
+        loop_body: &fn() -> ~fn(uint, A, &mut Repair<A, T>) -> A,
+        predictor: &fn() -> ~fn(uint) -> A)
+        -> (SpecStats, ~[A], ~[T]) {
+    specfold_repair_on(&TaskExecutor, iters, loop_body, predictor, |p, a| *p == *a)
+}
 
-    // The future is (prediction, result)
-    let mut results: ~[Future<(A, A)>] = std::vec::with_capacity(iters);
-    let mut stats = SpecStats { iters: iters,
-                                mispredictions: std::vec::from_elem(iters, false) };
-    for i in range(0, iters) {
-        let fut = do Future::spawn_with((predictor(), loop_body())) |(p,l)| {
-            let prediction = p(i);
-            let res = l(i, prediction.clone());
-            (prediction, res)
+/**
+ * The general form of `specfold_repair`.
+ */
+pub fn specfold_repair_on<E: Executor, A: Clone + Send, T: Send>(
+        exec: &E,
+        iters: uint,
+        loop_body: &fn() -> ~fn(uint, A, &mut Repair<A, T>) -> A,
+        predictor: &fn() -> ~fn(uint) -> A,
+        valid: &fn(&A, &A) -> bool)
+        -> (SpecStats, ~[A], ~[T]) {
+    let mut results = vec::with_capacity(iters);
+    let mut output = ~[];
+    let collect: &fn(uint, A, ~[T]) = |_, res, items| {
+        results.push(res);
+        output.push_all_move(items);
+    };
+    let stats = run_fold(exec, num::max(iters, 1), Some(iters), loop_body, predictor, valid, [],
+                         |_| false, true, collect);
+    (stats, results, output)
+}
+
+/**
+ * Like `specfold_collect`, but only keep `window` iterations in flight at
+ * once, for loops with far more iterations than there are cores.
+ *
+ * Committed results are handed to `publish` in iteration order instead of
+ * being returned.
+ */
+pub fn specfold_window<A: Eq + Clone + Send>(window: uint,
+                                             iters: uint,
+                                             loop_body: &fn() -> ~fn(uint, A) -> A,
+                                             predictor: &fn() -> ~fn(uint) -> A,
+                                             publish: &fn(uint, A)) -> SpecStats {
+    specfold_window_on(&TaskExecutor, window, iters, || ignore_output(loop_body()), predictor,
+                       |p, a| *p == *a, |i, res, _| publish(i, res))
+}
+
+/**
+ * The general form of `specfold_window`, and the loop which all the other
+ * `specfold` functions are built on.
+ *
+ * Iteration `i + window` is only started once iteration `i` has been
+ * committed, and the runs of an iteration are thrown away as soon as it is
+ * committed, so apart from the statistics, memory use is bounded by the
+ * window rather than the number of iterations.
+ */
+pub fn specfold_window_on<E: Executor, A: Clone + Send, T: Send>(
+        exec: &E,
+        window: uint,
+        iters: uint,
+        loop_body: &fn() -> ~fn(uint, A, &mut Output<T>) -> A,
+        predictor: &fn() -> ~fn(uint) -> A,
+        valid: &fn(&A, &A) -> bool,
+        publish: &fn(uint, A, ~[T]))
+        -> SpecStats {
+    fold_on(exec, window, iters, loop_body, predictor, valid, [], |_| false, publish)
+}
+
+/**
+ * Speculatively run `state = loop_body()(i, state)` for `i = 0, 1, ...` for
+ * as long as `cond()(&state)` holds, starting from `init`, and return the state
+ * after every iteration.
+ *
+ * `predictor()(i)` guesses the input of iteration `i`, or returns `None` if it
+ * guesses that the loop will have ended before `i`. Like `specfold_window`, at
+ * most `window` iterations are in flight at once, and each speculative run
+ * finds out for itself whether its iteration happens, by checking `cond` on
+ * its input. Iterations found to be past the real end of the loop are thrown
+ * away, so exactly the iterations a sequential loop would run are committed.
+ * An iteration the predictor wrongly guessed would not run is counted as
+ * mispredicted.
+ */
+pub fn spec_while<A: Eq + Clone + Send>(init: A,
+                                        window: uint,
+                                        cond: &fn() -> ~fn(&A) -> bool,
+                                        loop_body: &fn() -> ~fn(uint, A) -> A,
+                                        predictor: &fn() -> ~fn(uint) -> Option<A>)
+                                        -> (SpecStats, ~[A]) {
+    spec_while_on(&TaskExecutor, init, window, cond, loop_body, predictor, |p, a| *p == *a)
+}
+
+/**
+ * The general form of `spec_while`.
+ */
+pub fn spec_while_on<E: Executor, A: Clone + Send>(exec: &E,
+                                                   init: A,
+                                                   window: uint,
+                                                   cond: &fn() -> ~fn(&A) -> bool,
+                                                   loop_body: &fn() -> ~fn(uint, A) -> A,
+                                                   predictor: &fn() -> ~fn(uint) -> Option<A>,
+                                                   valid: &fn(&A, &A) -> bool)
+                                                   -> (SpecStats, ~[A]) {
+    // Every committed state is `Some`, up to the `None` of the first iteration
+    // which does not happen
+    let body: &fn() -> ~fn(uint, Option<A>, &mut Repair<Option<A>, ()>) -> Option<A> = || {
+        let (c, l) = (cond(), loop_body());
+        |i, state, _| match state {
+            Some(state) => if c(&state) { Some(l(i, state)) } else { None },
+            None => None
+        }
+    };
+    let guess: &fn() -> ~fn(uint) -> Option<A> = || {
+        let p = predictor();
+        let init = init.clone();
+        |i| if i == 0 { Some(init.clone()) } else { p(i) }
+    };
+    let valid: &fn(&Option<A>, &Option<A>) -> bool = |p, a| {
+        match (p, a) {
+            (&Some(ref p), &Some(ref a)) => valid(p, a),
+            _ => false
+        }
+    };
+
+    let mut results = ~[];
+    let collect: &fn(uint, Option<A>, ~[()]) = |_, state, _| {
+        match state {
+            Some(state) => results.push(state),
+            None => ()
+        }
+    };
+    let mut stats = run_fold(exec, window, None, body, guess, valid, [], |s| s.is_none(), false,
+                             collect);
+
+    // Leave out the iteration which found the loop had ended
+    let n = results.len();
+    stats.iters = n;
+    stats.mispredictions.truncate(n);
+    stats.times.truncate(n);
+    stats.error_at = None;
+    (stats, results)
+}
+
+/**
+ * `specfold_window_on`, except that the speculative run of every iteration in
+ * `forced` is treated as a misprediction, whatever its input was, and that the
+ * loop stops at the first committed result for which `is_err` holds. No run
+ * is ever started from such a result.
+ */
+fn fold_on<E: Executor, A: Clone + Send, T: Send>(
+        exec: &E,
+        window: uint,
+        iters: uint,
+        loop_body: &fn() -> ~fn(uint, A, &mut Output<T>) -> A,
+        predictor: &fn() -> ~fn(uint) -> A,
+        valid: &fn(&A, &A) -> bool,
+        forced: &[uint],
+        is_err: &fn(&A) -> bool,
+        publish: &fn(uint, A, ~[T]))
+        -> SpecStats {
+    let body: &fn() -> ~fn(uint, A, &mut Repair<A, T>) -> A = || plain_body(loop_body());
+    run_fold(exec, window, Some(iters), body, predictor, valid, forced, is_err, false, publish)
+}
+
+// The engine behind every `specfold`. With `repair`, re-executions are given
+// the checkpoints of the speculative run to catch up with. Without `iters`,
+// the loop only stops once `is_err` holds for a committed result, and the
+// stats only cover the committed iterations.
+fn run_fold<E: Executor, A: Clone + Send, T: Send>(
+        exec: &E,
+        window: uint,
+        iters: Option<uint>,
+        loop_body: &fn() -> ~fn(uint, A, &mut Repair<A, T>) -> A,
+        predictor: &fn() -> ~fn(uint) -> A,
+        valid: &fn(&A, &A) -> bool,
+        forced: &[uint],
+        is_err: &fn(&A) -> bool,
+        repair: bool,
+        publish: &fn(uint, A, ~[T]))
+        -> SpecStats {
+    assert!(window > 0);
+    let (port, chan) = stream();
+    let (bound, known) = match iters {
+        Some(n) => (n, n),
+        None => (uint::max_value, 0)
+    };
+    let mut fold = Fold { exec: exec,
+                          loop_body: loop_body,
+                          predictor: predictor,
+                          valid: valid,
+                          forced: forced,
+                          is_err: is_err,
+                          repair: repair,
+                          publish: publish,
+                          chan: SharedChan::new(chan),
+                          runs: RingBuf::new(),
+                          previous: None,
+                          stats: SpecStats::new(known),
+                          iters: bound,
+                          window: window,
+                          next: 0 };
+
+    for i in range(0, num::min(window, bound)) {
+        fold.start(i);
+    }
+
+    // Validate as results come in, rather than in iteration order
+    while fold.next < bound && fold.stats.error_at.is_none() {
+        if !port.peek() { exec.wait() }
+        let msg = port.recv();
+        let start = precise_time_ns();
+        fold.finish(msg);
+        fold.stats.validate_ns += precise_time_ns() - start;
+    }
+
+    if iters.is_none() {
+        // Drop the iterations started past the one which stopped the loop
+        fold.stats.iters = fold.next;
+        fold.stats.mispredictions.truncate(fold.next);
+        fold.stats.times.truncate(fold.next);
+    }
+    fold.stats.span.end = precise_time_ns();
+    util::replace(&mut fold.stats, SpecStats::new(0))
+}
+
+/**
+ * Like `specfold_collect`, for a loop body which can fail with an `Err`.
+ *
+ * The loop stops at the first iteration whose validated run returns an `Err`,
+ * which is returned instead of the results, and `error_at` is set to that
+ * iteration. An `Err` from a run which was thrown away, because its input was
+ * mispredicted, is only counted in `errors`.
+ */
+pub fn try_specfold<A: Eq + Clone + Send, E: Clone + Send>(
+        iters: uint,
+        loop_body: &fn() -> ~fn(uint, A) -> Result<A, E>,
+        predictor: &fn() -> ~fn(uint) -> A)
+        -> (SpecStats, Result<~[A], E>) {
+    let body: &fn() -> ~fn(uint, Result<A, E>, &mut Output<()>) -> Result<A, E> = || {
+        let l = loop_body();
+        // Runs are never started from an `Err`, so every `Err` a run ends
+        // with came from the loop body
+        |i, input, _| match input {
+            Ok(val) => l(i, val),
+            Err(e) => Err(e)
+        }
+    };
+    let guess: &fn() -> ~fn(uint) -> Result<A, E> = || {
+        let p = predictor();
+        |i| Ok(p(i))
+    };
+    let valid: &fn(&Result<A, E>, &Result<A, E>) -> bool = |p, a| {
+        match (p, a) {
+            (&Ok(ref p), &Ok(ref a)) => *p == *a,
+            _ => false
+        }
+    };
+
+    let mut results = vec::with_capacity(iters);
+    let mut error = None;
+    let collect: &fn(uint, Result<A, E>, ~[()]) = |_, res, _| {
+        match res {
+            Ok(val) => results.push(val),
+            Err(e) => error = Some(e)
+        }
+    };
+    let stats = fold_on(&TaskExecutor, num::max(iters, 1), iters, body, guess, valid, [],
+                        |res| res.is_err(), collect);
+    match error {
+        Some(e) => (stats, Err(e)),
+        None => (stats, Ok(results))
+    }
+}
+
+/**
+ * Like `specfold_collect`, but without blocking the calling task.
+ *
+ * The loop is run from a new task, so the closure factories have to be owned
+ * rather than borrowed.
+ */
+pub fn specfold_future<A: Eq + Clone + Send>(iters: uint,
+                                             loop_body: ~fn() -> ~fn(uint, A) -> A,
+                                             predictor: ~fn() -> ~fn(uint) -> A)
+                                             -> Future<(SpecStats, ~[A])> {
+    do Future::spawn_with((loop_body, predictor)) |(loop_body, predictor)| {
+        specfold_collect(iters, || loop_body(), || predictor())
+    }
+}
+
+/**
+ * Run `f` in a new task, returning `None` if it fails.
+ */
+fn isolate<T: Send>(f: ~fn() -> T) -> Option<T> {
+    match task::try(f) {
+        Ok(res) => Some(res),
+        Err(_) => None
+    }
+}
+
+// Adapt a loop body which does not write any output
+fn ignore_output<A>(body: ~fn(uint, A) -> A) -> ~fn(uint, A, &mut Output<()>) -> A {
+    |i, val, _| body(i, val)
+}
+
+// Adapt a loop body which does not use checkpoints
+fn plain_body<A, T>(body: ~fn(uint, A, &mut Output<T>) -> A) -> ~fn(uint, A, &mut Repair<A, T>) -> A {
+    |i, val, ctx| body(i, val, &mut ctx.output)
+}
+
+/**
+ * Output written by one run of a loop body.
+ *
+ * Each run gets a fresh buffer, and the caller only sees the contents of the
+ * buffer belonging to the run which was committed.
+ */
+pub struct Output<T> {
+    priv items: ~[T]
+}
+
+impl<T> Output<T> {
+    pub fn new() -> Output<T> {
+        Output { items: ~[] }
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.items.push(item);
+    }
+
+    pub fn push_all_move(&mut self, items: ~[T]) {
+        self.items.push_all_move(items);
+    }
+
+    pub fn len(&self) -> uint {
+        self.items.len()
+    }
+}
+
+/**
+ * Output and checkpoints of one run of a loop body for `specfold_repair`.
+ *
+ * The body calls `checkpoint` with its state whenever the rest of the
+ * iteration depends on nothing but that state. On the speculative run this
+ * just records the state. On a re-execution, once the state matches one
+ * recorded by the speculative run, `checkpoint` returns `true`: the body
+ * should return straight away, and the rest of the speculative run's output
+ * and its result are used instead.
+ */
+pub struct Repair<A, T> {
+    priv output: Output<T>,
+    // The states passed to `checkpoint`, with how much output had been
+    // written at the time
+    priv checkpoints: ~[(A, uint)],
+    // The checkpoints of the speculative run, when this is a re-execution
+    priv target: ~[(A, uint)],
+    // Index into `target` of the checkpoint this run caught up with
+    priv matched: Option<uint>
+}
+
+impl<A, T> Repair<A, T> {
+    fn new(target: ~[(A, uint)]) -> Repair<A, T> {
+        Repair { output: Output::new(), checkpoints: ~[], target: target, matched: None }
+    }
+
+    pub fn push(&mut self, item: T) {
+        self.output.push(item);
+    }
+
+    pub fn push_all_move(&mut self, items: ~[T]) {
+        self.output.push_all_move(items);
+    }
+
+    pub fn len(&self) -> uint {
+        self.output.len()
+    }
+}
+
+impl<A: Eq + Clone, T> Repair<A, T> {
+    /**
+     * Record that the run has reached `state`. Returns `true` if the body
+     * should stop, because the speculative run already went on from the same
+     * state.
+     */
+    pub fn checkpoint(&mut self, state: &A) -> bool {
+        match self.target.iter().position(|&(ref s, _)| *s == *state) {
+            Some(k) => {
+                self.matched = Some(k);
+                true
+            }
+            None => {
+                self.checkpoints.push((state.clone(), self.output.len()));
+                false
+            }
+        }
+    }
+}
+
+enum Outcome<A> {
+    Running,
+    Finished(A),
+    Failed
+}
+
+/**
+ * One execution of a single iteration of a `specfold` loop.
+ *
+ * The input of the initial, speculative run is not known until its predictor
+ * has run on the executor, and is never known if the predictor failed.
+ */
+struct Run<A, T> {
+    input: Option<A>,
+    result: Outcome<A>,
+    output: ~[T],
+    checkpoints: ~[(A, uint)],
+    // For a re-execution which caught up with the speculative run, how much
+    // of the speculative run's output to skip before splicing in the rest
+    splice: Option<uint>,
+    // Whether the run was started from the committed result of the previous
+    // iteration, so that its input needs no validating
+    exact: bool,
+    // How long the loop body took, once the run has finished
+    time: Span
+}
+
+impl<A, T> Run<A, T> {
+    fn new(input: Option<A>) -> Run<A, T> {
+        Run { input: input, result: Running, output: ~[], checkpoints: ~[], splice: None,
+              exact: false, time: Span::now() }
+    }
+
+    fn running(&self) -> bool {
+        match self.result { Running => true, _ => false }
+    }
+
+    fn failed(&self) -> bool {
+        match self.result { Failed => true, _ => false }
+    }
+}
+
+// Sent back to the validating task when a run finishes. Both the predictor
+// and the loop body are run in their own task, and `input` or `result` are
+// `None` if they failed.
+struct RunResult<A, T> {
+    iter: uint,
+    run: uint,
+    input: Option<A>,
+    result: Option<(A, Repair<A, T>)>,
+    // Only the speculative run calls the predictor
+    predictor: Option<Span>,
+    body: Span
+}
+
+/**
+ * The validation state of a `specfold` loop.
+ *
+ * Every iteration starts with one speculative run, and at most `window`
+ * iterations are started but not committed at any time. Whenever a run
+ * finishes, its result is used straight away: if it is the newest run of its
+ * iteration and no run of the next iteration was started with a consistent
+ * input, the next iteration is re-executed with it on the executor. Iterations
+ * are committed in order once the previous iteration is committed and a run
+ * with a valid input has finished, so the committed results and
+ * mispredictions are the same as if validation were done sequentially.
+ */
+struct Fold<'self, E, A, T> {
+    exec: &'self E,
+    loop_body: &'self fn() -> ~fn(uint, A, &mut Repair<A, T>) -> A,
+    predictor: &'self fn() -> ~fn(uint) -> A,
+    valid: &'self fn(&A, &A) -> bool,
+    // Iterations whose speculative run never counts as valid
+    forced: &'self [uint],
+    // Results which stop the loop once committed
+    is_err: &'self fn(&A) -> bool,
+    // Whether re-executions try to catch up with the speculative run
+    repair: bool,
+    publish: &'self fn(uint, A, ~[T]),
+    chan: SharedChan<RunResult<A, T>>,
+    // The runs of each iteration which has been started but not committed,
+    // starting with iteration `next`
+    runs: RingBuf<~[Run<A, T>]>,
+    // The committed result of iteration `next - 1`
+    previous: Option<A>,
+    stats: SpecStats,
+    // How many iterations there are at most
+    iters: uint,
+    window: uint,
+    next: uint
+}
+
+impl<'self, E: Executor, A: Clone + Send, T: Send> Fold<'self, E, A, T> {
+    fn runs_of<'a>(&'a self, i: uint) -> &'a ~[Run<A, T>] {
+        self.runs.get(i - self.next)
+    }
+
+    fn run_mut<'a>(&'a mut self, i: uint, run: uint) -> &'a mut Run<A, T> {
+        let runs = self.runs.get_mut(i - self.next);
+        &mut (*runs)[run]
+    }
+
+    // Whether iteration `i` has been started
+    fn started(&self, i: uint) -> bool {
+        i < self.next + self.runs.len()
+    }
+
+    /**
+     * Start the speculative run of iteration `i`.
+     */
+    fn start(&mut self, i: uint) {
+        // Open-ended loops only find out how many iterations there are as they
+        // go
+        if i == self.stats.times.len() {
+            self.stats.mispredictions.push(false);
+            self.stats.times.push(IterTimes::new());
+        }
+        self.runs.push_back(~[Run::new(None)]);
+        let args = Cell::new(((self.predictor)(), (self.loop_body)(), self.chan.clone()));
+        do self.exec.execute {
+            let (p, l, chan) = args.take();
+            let start = precise_time_ns();
+            let prediction = isolate(|| p(i));
+            let predicted = precise_time_ns();
+            let res = match prediction {
+                Some(ref guess) => {
+                    let guess = guess.clone();
+                    isolate(|| {
+                        let mut ctx = Repair::new(~[]);
+                        let res = l(i, guess.clone(), &mut ctx);
+                        (res, ctx)
+                    })
+                }
+                None => None
+            };
+            chan.try_send(RunResult { iter: i,
+                                      run: 0,
+                                      input: prediction,
+                                      result: res,
+                                      predictor: Some(Span { start: start, end: predicted }),
+                                      body: Span { start: predicted, end: precise_time_ns() } });
+        }
+    }
+
+    /**
+     * Re-execute iteration `i` with `input` on the executor. `exact` says
+     * whether `input` is the committed result of the previous iteration.
+     */
+    fn launch(&mut self, i: uint, input: A, exact: bool) {
+        let run = self.runs_of(i).len();
+        // Give the checkpoints of the speculative run to catch up with
+        let target = match self.runs_of(i)[0].result {
+            Finished(_) if self.repair => self.runs_of(i)[0].checkpoints.clone(),
+            _ => ~[]
         };
-        results.push(fut);
+        let mut r = Run::new(Some(input.clone()));
+        r.exact = exact;
+        self.runs.get_mut(i - self.next).push(r);
+        let args = Cell::new(((self.loop_body)(), input, target, self.chan.clone()));
+        do self.exec.execute {
+            let (body, input, target, chan) = args.take();
+            let start = precise_time_ns();
+            let arg = input.clone();
+            let res = isolate(|| {
+                let mut ctx = Repair::new(target.clone());
+                let res = body(i, arg.clone(), &mut ctx);
+                (res, ctx)
+            });
+            chan.try_send(RunResult { iter: i,
+                                      run: run,
+                                      input: Some(input),
+                                      result: res,
+                                      predictor: None,
+                                      body: Span { start: start, end: precise_time_ns() } });
+        }
     }
 
-    // Wait for the first result. This is necessary in the case that `iters` is
-    // 1, since then the validation loop will not run.
-    if iters == 1 { results[0].get_ref(); }
+    /**
+     * Find a run of iteration `i` whose input is valid given that the previous
+     * iteration produced `actual`. A run started from the committed result
+     * always is, even if `valid` does not think a value is valid for itself
+     * (a NaN, say).
+     */
+    fn matching_run(&self, i: uint, actual: &A) -> Option<uint> {
+        let forced = self.forced.contains(&i);
+        do self.runs_of(i).iter().enumerate().position |(run, r)| {
+            r.exact || match r.input {
+                Some(ref input) => !(forced && run == 0) && (self.valid)(input, actual),
+                None => false
+            }
+        }
+    }
+
+    /**
+     * Record the result of a run and make whatever progress it allows.
+     */
+    fn finish(&mut self, msg: RunResult<A, T>) {
+        let RunResult { iter: i, run: run, input: input, result: result,
+                        predictor: predictor, body: body } = msg;
+        // Nothing more can come of a run of an iteration which was committed
+        // while it was still going
+        if i < self.next { return }
+
+        let newest = run == self.runs_of(i).len() - 1;
+        {
+            let r = self.run_mut(i, run);
+            r.input = input;
+            r.time = body;
+        }
+        match predictor {
+            Some(span) => self.stats.times[i].predictor = span,
+            None => ()
+        }
+
+        match result {
+            Some((res, ctx)) => {
+                let Repair { output: output, checkpoints: checkpoints, target: _,
+                             matched: matched } = ctx;
+                // A run which caught up with the speculative run ends the same
+                // way it does
+                let (res, splice) = match matched {
+                    Some(k) => {
+                        let spec = &self.runs_of(i)[0];
+                        let splice = match spec.checkpoints[k] { (_, len) => len };
+                        match spec.result {
+                            Finished(ref spec_res) => (spec_res.clone(), Some(splice)),
+                            _ => fail!("repaired a speculative run which has not finished")
+                        }
+                    }
+                    None => (res, None)
+                };
+                {
+                    let r = self.run_mut(i, run);
+                    r.result = Finished(res.clone());
+                    r.output = output.items;
+                    r.checkpoints = checkpoints;
+                    r.splice = splice;
+                }
+
+                // Feed this result forward to the next iteration, if its
+                // prediction is already known to disagree with it. A result
+                // which would stop the loop has no next iteration.
+                if newest && !(self.is_err)(&res) && self.started(i + 1)
+                    && !self.runs_of(i + 1)[0].running()
+                    && self.matching_run(i + 1, &res).is_none() {
+                    self.launch(i + 1, res, false);
+                }
+            }
+            None => self.run_mut(i, run).result = Failed
+        }
 
-    // Validate. Sequentially, for now
-    for i in range(1, iters) {
-        let (_, previous) = results[i - 1].get();
-        let (prediction, _) = results[i].get();
-        if previous != prediction {
-            stats.mispredictions[i] = true;
-            let res = loop_body()(i, previous.clone());
-            results[i] = Future::from_value((previous, res));
+        // If this was the speculative run, check it against the newest
+        // finished run of the previous iteration
+        if run == 0 && i > self.next {
+            let previous = match self.runs_of(i - 1).last().result {
+                Finished(ref res) if !(self.is_err)(res) => Some(res.clone()),
+                _ => None
+            };
+            match previous {
+                Some(res) => if self.matching_run(i, &res).is_none() { self.launch(i, res, false) },
+                None => ()
+            }
+        }
+
+        self.advance();
+    }
+
+    /**
+     * Commit as many iterations as possible.
+     */
+    fn advance(&mut self) {
+        while self.next < self.iters && self.stats.error_at.is_none() {
+            let i = self.next;
+            if self.runs_of(i)[0].running() { return }
+
+            let run = match self.previous.clone() {
+                // There is nothing to check the first iteration against
+                None => 0,
+                Some(previous) => {
+                    self.stats.mispredictions[i] = match self.runs_of(i)[0].input {
+                        Some(ref prediction) => {
+                            self.forced.contains(&i) || !(self.valid)(prediction, &previous)
+                        }
+                        None => true
+                    };
+                    match self.matching_run(i, &previous) {
+                        Some(run) => run,
+                        None => {
+                            self.launch(i, previous, true);
+                            return
+                        }
+                    }
+                }
+            };
+
+            let result = match self.runs_of(i)[run].result {
+                Running => return,
+                Finished(ref res) => res.clone(),
+                Failed => fail!(format!("iteration {} of specfold failed", i))
+            };
+
+            let mut runs = self.runs.pop_front().unwrap();
+            self.stats.times[i].committed = run;
+            self.stats.times[i].body = runs[0].time.clone();
+            self.stats.times[i].reexecs = runs.slice_from(1).iter().map(|r| r.time.clone()).collect();
+            // Any failure or error left over was on a run which is being
+            // thrown away
+            self.stats.failures += runs.iter().count(|r| r.failed());
+            let errors = do runs.iter().enumerate().count |(k, r)| {
+                match r.result {
+                    Finished(ref res) => k != run && (self.is_err)(res),
+                    _ => false
+                }
+            };
+            self.stats.errors += errors;
+            let mut output = util::replace(&mut runs[run].output, ~[]);
+            match runs[run].splice {
+                Some(from) => {
+                    let spec_output = util::replace(&mut runs[0].output, ~[]);
+                    output.push_all_move(spec_output.move_iter().skip(from).collect());
+                    self.stats.repairs += 1;
+                }
+                None => ()
+            }
+
+            if (self.is_err)(&result) {
+                self.stats.error_at = Some(i);
+            }
+            self.previous = Some(result.clone());
+            self.next += 1;
+            if i + self.window < self.iters && self.stats.error_at.is_none() {
+                self.start(i + self.window);
+            }
+            (self.publish)(i, result, output);
         }
     }
-    stats
 }
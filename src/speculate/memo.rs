@@ -0,0 +1,161 @@
+use std::util;
+use std::hashmap::HashMap;
+use std::to_bytes::IterBytes;
+use extra::container::Deque;
+use extra::future::Future;
+use extra::ringbuf::RingBuf;
+
+struct Entry<B> {
+    value: B,
+    // The value of the clock when the entry was last used
+    used: uint
+}
+
+/**
+ * Remembers the results of a consumer for recently seen inputs.
+ *
+ * Keep one `Memo` per consumer and call `spec` through it. Before running the
+ * consumer on the guess or the real value, the cache is checked, so a
+ * misprediction whose real value was seen before costs no re-execution at
+ * all. At most `capacity` results are kept, and the least recently used one is
+ * dropped to make room. Only use it with a consumer whose result depends on
+ * nothing but its input.
+ */
+pub struct Memo<A, B> {
+    priv capacity: uint,
+    priv entries: HashMap<A, Entry<B>>,
+    // Every use of an entry, with the clock at the time, oldest first. Only
+    // the newest use of an entry which is still there counts.
+    priv uses: RingBuf<(A, uint)>,
+    priv clock: uint,
+    priv hits: uint,
+    priv misses: uint
+}
+
+impl<A: Eq + IterBytes + Clone + Send, B: Clone> Memo<A, B> {
+    pub fn new(capacity: uint) -> Memo<A, B> {
+        Memo { capacity: capacity,
+               entries: HashMap::new(),
+               uses: RingBuf::new(),
+               clock: 0,
+               hits: 0,
+               misses: 0 }
+    }
+
+    pub fn len(&self) -> uint { self.entries.len() }
+
+    /**
+     * How many consumer runs were answered from the cache.
+     */
+    pub fn hits(&self) -> uint { self.hits }
+
+    /**
+     * How many times the consumer actually had to be run.
+     */
+    pub fn misses(&self) -> uint { self.misses }
+
+    /**
+     * The remembered result for `input`, if any.
+     */
+    pub fn find(&mut self, input: &A) -> Option<B> {
+        let clock = self.clock + 1;
+        let value = match self.entries.find_mut(input) {
+            Some(entry) => {
+                entry.used = clock;
+                entry.value.clone()
+            }
+            None => return None
+        };
+        self.used(input.clone(), clock);
+        Some(value)
+    }
+
+    /**
+     * Remember `value` as the result for `input`, dropping the least recently
+     * used result if the cache is full.
+     */
+    pub fn insert(&mut self, input: A, value: B) {
+        if self.capacity == 0 { return }
+        if self.entries.len() >= self.capacity && !self.entries.contains_key(&input) {
+            self.evict();
+        }
+        let clock = self.clock + 1;
+        self.entries.insert(input.clone(), Entry { value: value, used: clock });
+        self.used(input, clock);
+    }
+
+    /**
+     * Like `speculate::spec`, but look up the consumer's result for the
+     * guess and for the real value before running it, and remember the
+     * results of any runs.
+     */
+    pub fn spec(&mut self,
+                producer: ~fn() -> A,
+                predictor: ~fn() -> A,
+                consumer: ~fn(A) -> B) -> B {
+        let producer_result = Future::spawn(producer);
+        let prediction = predictor();
+        let speculative_result = self.call(&consumer, prediction.clone());
+        let real_value = producer_result.unwrap();
+
+        if prediction == real_value {
+            speculative_result
+        } else {
+            self.call(&consumer, real_value)
+        }
+    }
+
+    // Whether `stamp` is the newest use of an entry which is still there
+    fn current(&self, input: &A, stamp: uint) -> bool {
+        match self.entries.find(input) {
+            Some(entry) => entry.used == stamp,
+            None => false
+        }
+    }
+
+    fn used(&mut self, input: A, clock: uint) {
+        self.clock = clock;
+        self.uses.push_back((input, clock));
+        // Once most uses are out of date, drop them all in one go, which
+        // costs a constant amount per use on average
+        if self.uses.len() > 2 * self.entries.len() {
+            let mut uses = util::replace(&mut self.uses, RingBuf::new());
+            loop {
+                match uses.pop_front() {
+                    Some((key, stamp)) => if self.current(&key, stamp) {
+                        self.uses.push_back((key, stamp));
+                    },
+                    None => break
+                }
+            }
+        }
+    }
+
+    // Drop the least recently used entry
+    fn evict(&mut self) {
+        loop {
+            match self.uses.pop_front() {
+                Some((key, stamp)) => if self.current(&key, stamp) {
+                    self.entries.remove(&key);
+                    return
+                },
+                None => return
+            }
+        }
+    }
+
+    fn call(&mut self, consumer: &~fn(A) -> B, input: A) -> B {
+        match self.find(&input) {
+            Some(value) => {
+                self.hits += 1;
+                value
+            }
+            None => {
+                self.misses += 1;
+                let value = (*consumer)(input.clone());
+                self.insert(input, value.clone());
+                value
+            }
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use std::cell::Cell;
+use extra::future::Future;
+use extra::time::precise_time_ns;
+
+use super::{IterTimes, Span, SpecStats};
+
+// What each stage reports once it has its validated result: whether its
+// guess was wrong, and how long it took
+type Report = (bool, IterTimes);
+
+/**
+ * A chain of stages, each consuming the output of the one before it.
+ *
+ * Stages added with `then_spec` have a predictor for their input, so they
+ * start straight away on the guess instead of waiting for the stage before.
+ * When the real input arrives, the guess is checked, and only if it was wrong
+ * is the stage run again; a stage further down with a correct guess of its
+ * own keeps its speculative result. So a misprediction at stage `k` never
+ * re-executes anything upstream of `k`.
+ *
+ * Every stage runs in its own task. The stage is a function returning the
+ * actual stage function, since it may have to be run twice.
+ */
+pub struct Pipeline<A, B> {
+    priv stages: uint,
+    priv run: ~fn(A) -> Future<(B, ~[Report])>
+}
+
+impl<A: Send, B: Send> Pipeline<A, B> {
+    /**
+     * A pipeline whose first stage is `stage`.
+     */
+    pub fn new(stage: ~fn() -> ~fn(A) -> B) -> Pipeline<A, B> {
+        let stage = Cell::new(stage);
+        Pipeline {
+            stages: 1,
+            run: |input| {
+                do Future::spawn_with((input, stage.take())) |(input, stage)| {
+                    let mut times = IterTimes::new();
+                    let start = precise_time_ns();
+                    let res = stage()(input);
+                    times.body = Span { start: start, end: precise_time_ns() };
+                    (res, ~[(false, times)])
+                }
+            }
+        }
+    }
+
+    /**
+     * Start every stage on `input`, and wait for the validated output of the
+     * last one.
+     *
+     * Stage `k` is iteration `k` of the returned stats: `mispredictions[k]`
+     * says whether its guess was wrong, and `times[k]` when it ran.
+     */
+    pub fn run(self, input: A) -> (SpecStats, B) {
+        let mut stats = SpecStats::new(self.stages);
+        let (res, reports) = (self.run)(input).unwrap();
+        for (k, (mispredicted, times)) in reports.move_iter().enumerate() {
+            stats.mispredictions[k] = mispredicted;
+            stats.times[k] = times;
+        }
+        stats.span.end = precise_time_ns();
+        (stats, res)
+    }
+
+    /**
+     * Add a stage which waits for the output of the last one.
+     */
+    pub fn then<C: Send>(self, stage: ~fn() -> ~fn(B) -> C) -> Pipeline<A, C> {
+        let stage = Cell::new(stage);
+        do self.chain |upstream| {
+            do Future::spawn_with((upstream, stage.take())) |(upstream, stage)| {
+                let (actual, mut reports) = upstream.unwrap();
+                let mut times = IterTimes::new();
+                let start = precise_time_ns();
+                let res = stage()(actual);
+                times.body = Span { start: start, end: precise_time_ns() };
+                reports.push((false, times));
+                (res, reports)
+            }
+        }
+    }
+
+    // Add a stage, which `start` starts given the future output of the stages
+    // before it
+    fn chain<C: Send>(self,
+                      start: ~fn(Future<(B, ~[Report])>) -> Future<(C, ~[Report])>)
+                      -> Pipeline<A, C> {
+        let Pipeline { stages: stages, run: upstream } = self;
+        let args = Cell::new((upstream, start));
+        Pipeline {
+            stages: stages + 1,
+            run: |input| {
+                let (upstream, start) = args.take();
+                // Start the stages before this one first, so that they all
+                // run at once
+                start(upstream(input))
+            }
+        }
+    }
+}
+
+impl<A: Send, B: Eq + Clone + Send> Pipeline<A, B> {
+    /**
+     * Add a stage which starts straight away on the output of the last one
+     * guessed by `predictor`.
+     */
+    pub fn then_spec<C: Send>(self,
+                              stage: ~fn() -> ~fn(B) -> C,
+                              predictor: ~fn() -> B) -> Pipeline<A, C> {
+        let args = Cell::new((stage, predictor));
+        do self.chain |upstream| {
+            let (stage, predictor) = args.take();
+            let speculative = do Future::spawn_with((predictor, stage())) |(p, s)| {
+                let start = precise_time_ns();
+                let guess = p();
+                let predicted = precise_time_ns();
+                let res = s(guess.clone());
+                (guess, res, Span { start: start, end: predicted },
+                 Span { start: predicted, end: precise_time_ns() })
+            };
+
+            let parts = (upstream, speculative, stage);
+            do Future::spawn_with(parts) |(upstream, speculative, stage)| {
+                let (actual, mut reports) = upstream.unwrap();
+                let (guess, res, predictor, body) = speculative.unwrap();
+                let mut times = IterTimes::new();
+                times.predictor = predictor;
+                times.body = body;
+                let (res, mispredicted) = if guess == actual {
+                    (res, false)
+                } else {
+                    let start = precise_time_ns();
+                    let res = stage()(actual);
+                    times.reexecs.push(Span { start: start, end: precise_time_ns() });
+                    times.committed = 1;
+                    (res, true)
+                };
+                reports.push((mispredicted, times));
+                (res, reports)
+            }
+        }
+    }
+}
@@ -0,0 +1,229 @@
+/*!
+ * Reusable value predictors.
+ *
+ * Each predictor hands out prediction functions with `predict_fn`, which can be
+ * given straight to `specfold` as `|| p.predict_fn()`, and learns from the real
+ * values passed to `update`. State is shared between a predictor and the
+ * functions it has handed out, so a prediction made by an iteration started
+ * later (for instance by `specfold_window`) sees everything learned so far,
+ * including from earlier calls.
+ */
+
+use std::num;
+use std::hashmap::HashMap;
+use std::to_bytes::IterBytes;
+use extra::arc::RWArc;
+
+pub trait Predictor<A> {
+    /**
+     * A function guessing the input of iteration `i`.
+     */
+    fn predict_fn(&self) -> ~fn(uint) -> A;
+
+    /**
+     * Tell the predictor that the real input of iteration `i` was `actual`.
+     */
+    fn update(&self, i: uint, actual: &A);
+
+    /**
+     * Learn from the results of a `specfold_collect` call. The result of each
+     * iteration is the input of the next one.
+     */
+    fn learn(&self, results: &[A]) {
+        for (i, res) in results.iter().enumerate() {
+            self.update(i + 1, res);
+        }
+    }
+}
+
+/**
+ * Always predicts the same value.
+ */
+pub struct Constant<A> {
+    priv value: A
+}
+
+impl<A: Clone + Send> Constant<A> {
+    pub fn new(value: A) -> Constant<A> {
+        Constant { value: value }
+    }
+}
+
+impl<A: Clone + Send> Predictor<A> for Constant<A> {
+    fn predict_fn(&self) -> ~fn(uint) -> A {
+        let value = self.value.clone();
+        |_| value.clone()
+    }
+
+    fn update(&self, _: uint, _: &A) {}
+}
+
+/**
+ * Predicts that every iteration gets the most recent real value.
+ */
+pub struct LastValue<A> {
+    priv last: RWArc<A>
+}
+
+impl<A: Clone + Freeze + Send> LastValue<A> {
+    pub fn new(initial: A) -> LastValue<A> {
+        LastValue { last: RWArc::new(initial) }
+    }
+}
+
+impl<A: Clone + Freeze + Send> Predictor<A> for LastValue<A> {
+    fn predict_fn(&self) -> ~fn(uint) -> A {
+        let last = self.last.clone();
+        |_| last.read(|v| v.clone())
+    }
+
+    fn update(&self, _: uint, actual: &A) {
+        self.last.write(|v| *v = actual.clone());
+    }
+}
+
+struct StrideState<A> {
+    last_iter: uint,
+    last: A,
+    stride: A
+}
+
+/**
+ * Predicts that the value changes by the same amount every iteration.
+ *
+ * The stride is learned from the difference between the real values of
+ * consecutive iterations.
+ */
+pub struct Stride<A> {
+    priv state: RWArc<StrideState<A>>
+}
+
+impl<A: Add<A, A> + Sub<A, A> + Mul<A, A> + NumCast + Clone + Freeze + Send> Stride<A> {
+    /**
+     * Start out assuming iteration 0 gets `initial`, and each later iteration
+     * `stride` more than the one before.
+     */
+    pub fn new(initial: A, stride: A) -> Stride<A> {
+        Stride { state: RWArc::new(StrideState { last_iter: 0, last: initial, stride: stride }) }
+    }
+}
+
+impl<A: Add<A, A> + Sub<A, A> + Mul<A, A> + NumCast + Clone + Freeze + Send>
+    Predictor<A> for Stride<A> {
+    fn predict_fn(&self) -> ~fn(uint) -> A {
+        let state = self.state.clone();
+        |i| {
+            do state.read |s| {
+                if i >= s.last_iter {
+                    s.last + s.stride * num::cast(i - s.last_iter)
+                } else {
+                    s.last - s.stride * num::cast(s.last_iter - i)
+                }
+            }
+        }
+    }
+
+    fn update(&self, i: uint, actual: &A) {
+        do self.state.write |s| {
+            if i == s.last_iter + 1 {
+                s.stride = *actual - s.last;
+            }
+            s.last_iter = i;
+            s.last = actual.clone();
+        }
+    }
+}
+
+struct ContextState<A> {
+    order: uint,
+    // The input of iteration 0
+    initial: A,
+    // The real values of the last `order` iterations, oldest first
+    history: ~[A],
+    // The iteration whose input is the last element of `history`
+    last_iter: uint,
+    // The value which followed each context seen so far
+    table: HashMap<~[A], A>
+}
+
+impl<A: IterBytes + Eq + Clone> ContextState<A> {
+    /**
+     * Follow the table `steps` times from `context`, stopping early if a
+     * context has not been seen before.
+     */
+    fn walk(&self, context: ~[A], steps: uint) -> A {
+        let mut context = context;
+        for _ in range(0, steps) {
+            if context.len() < self.order { break }
+            let next = match self.table.find(&context) {
+                Some(next) => next.clone(),
+                None => break
+            };
+            context.shift();
+            context.push(next);
+        }
+        context.last().clone()
+    }
+
+    fn predict(&self, i: uint) -> A {
+        if i > self.last_iter {
+            self.walk(self.history.clone(), i - self.last_iter)
+        } else {
+            // A new loop has started, so go from the beginning
+            self.walk(~[self.initial.clone()], i)
+        }
+    }
+
+    fn update(&mut self, i: uint, actual: &A) {
+        if i != self.last_iter + 1 {
+            // A new loop has started, or some iterations were skipped. Only the
+            // input of the first iteration is known without being told.
+            self.history = if i == 1 { ~[self.initial.clone()] } else { ~[] };
+        }
+        if self.history.len() == self.order {
+            self.table.insert(self.history.clone(), actual.clone());
+            self.history.shift();
+        }
+        self.history.push(actual.clone());
+        self.last_iter = i;
+    }
+}
+
+/**
+ * Predicts the value which followed the same `order` values the last time
+ * they were seen (a finite context method predictor).
+ *
+ * The table is kept across calls, so a loop which goes through the same
+ * sequence of values every time is predicted perfectly from the second call
+ * on. Predictions fall back to the most recent value for contexts which have
+ * not been seen yet.
+ */
+pub struct ContextTable<A> {
+    priv state: RWArc<ContextState<A>>
+}
+
+impl<A: IterBytes + Eq + Clone + Freeze + Send> ContextTable<A> {
+    /**
+     * `initial` is the input of iteration 0 of every loop.
+     */
+    pub fn new(order: uint, initial: A) -> ContextTable<A> {
+        assert!(order > 0);
+        let state = ContextState { order: order,
+                                   initial: initial.clone(),
+                                   history: ~[initial],
+                                   last_iter: 0,
+                                   table: HashMap::new() };
+        ContextTable { state: RWArc::new(state) }
+    }
+}
+
+impl<A: IterBytes + Eq + Clone + Freeze + Send> Predictor<A> for ContextTable<A> {
+    fn predict_fn(&self) -> ~fn(uint) -> A {
+        let state = self.state.clone();
+        |i| state.read(|s| s.predict(i))
+    }
+
+    fn update(&self, i: uint, actual: &A) {
+        self.state.write(|s| s.update(i, actual));
+    }
+}
@@ -0,0 +1,130 @@
+use std::vec;
+use extra::time::precise_time_ns;
+
+/**
+ * When something started and finished, in nanoseconds as returned by
+ * `extra::time::precise_time_ns`.
+ */
+#[deriving(Clone, Eq)]
+pub struct Span {
+    start: u64,
+    end: u64
+}
+
+impl Span {
+    /**
+     * A span starting and ending now, to be filled in later.
+     */
+    pub fn now() -> Span {
+        let now = precise_time_ns();
+        Span { start: now, end: now }
+    }
+
+    pub fn ns(&self) -> u64 { self.end - self.start }
+}
+
+/**
+ * How long each part of one iteration took.
+ */
+#[deriving(Clone)]
+pub struct IterTimes {
+    predictor: Span,
+    // The run of the loop body on the predicted value
+    body: Span,
+    // Every later run of the loop body, in the order they were started. Runs
+    // which were still going when the iteration was committed have zero
+    // length.
+    reexecs: ~[Span],
+    // Which run was committed: 0 for `body`, otherwise `i + 1` for `reexecs[i]`
+    committed: uint
+}
+
+impl IterTimes {
+    pub fn new() -> IterTimes {
+        IterTimes { predictor: Span { start: 0, end: 0 },
+                    body: Span { start: 0, end: 0 },
+                    reexecs: ~[],
+                    committed: 0 }
+    }
+
+    pub fn reexec_ns(&self) -> u64 {
+        self.reexecs.iter().fold(0, |total, span| total + span.ns())
+    }
+
+    /**
+     * Time spent running the loop body whose results were thrown away.
+     */
+    pub fn wasted_ns(&self) -> u64 {
+        let total = self.body.ns() + self.reexec_ns();
+        let kept = if self.committed == 0 {
+            self.body.ns()
+        } else {
+            self.reexecs[self.committed - 1].ns()
+        };
+        total - kept
+    }
+}
+
+pub struct SpecStats {
+    iters: uint,
+    mispredictions: ~[bool],
+    // Index of the guess which matched the real value, for `spec_any`
+    winner: Option<uint>,
+    // When the producer ran, for `spec_timed`
+    producer: Option<Span>,
+    // Whether the work was run sequentially instead of speculatively
+    sequential: bool,
+    // How many speculative slots were granted, for calls made through a
+    // `Budget`
+    slots: uint,
+    // The whole call
+    span: Span,
+    times: ~[IterTimes],
+    // Time the calling task spent checking predictions and deciding what to
+    // run next, not counting time waiting for results
+    validate_ns: u64,
+    // Runs which failed but were thrown away because they were mispredicted
+    failures: uint,
+    // Like `failures`, but for runs which returned `Err`, for `try_spec` and
+    // `try_specfold`
+    errors: uint,
+    // The iteration whose validated run returned the `Err` handed back to the
+    // caller, if any
+    error_at: Option<uint>,
+    // Re-executions which caught up with the speculative run and reused the
+    // rest of it, for `specfold_repair`
+    repairs: uint
+}
+
+impl SpecStats {
+    pub fn new(iters: uint) -> SpecStats {
+        SpecStats { iters: iters,
+                    mispredictions: vec::from_elem(iters, false),
+                    winner: None,
+                    producer: None,
+                    sequential: false,
+                    slots: 0,
+                    span: Span::now(),
+                    times: vec::from_elem(iters, IterTimes::new()),
+                    validate_ns: 0,
+                    failures: 0,
+                    errors: 0,
+                    error_at: None,
+                    repairs: 0 }
+    }
+
+    /**
+     * Total time spent running the loop body on results which were thrown
+     * away.
+     */
+    pub fn wasted_ns(&self) -> u64 {
+        self.times.iter().fold(0, |total, t| total + t.wasted_ns())
+    }
+
+    /**
+     * Total time spent in the predictor.
+     */
+    pub fn predictor_ns(&self) -> u64 {
+        self.times.iter().fold(0, |total, t| total + t.predictor.ns())
+    }
+}
@@ -1,9 +1,13 @@
 extern mod speculate;
+extern mod extra;
 
-use std::task;
-use std::vec;
-use std::comm::{SharedPort, SharedChan, stream};
+use std::{task, vec};
 use speculate::*;
+use speculate::harness::*;
+use speculate::predictors::*;
+use extra::arc::Arc;
+use extra::future::Future;
+use extra::json;
 
 #[test]
 fn test_spec() {
@@ -11,109 +15,677 @@ fn test_spec() {
     assert!(spec(|| 2 + 2, || 1, |x| x + 2) == 6);
 }
 
-fn spawn_result_collector<T: Send + Default + Clone>(port: SharedPort<Option<(uint, T)>>, chan: Chan<~[T]>, size: uint) {
-    do task::spawn {
-        let mut results = vec::from_elem::<T>(size, Default::default());
-        loop {
-            match port.recv() {
-                Some((idx, val)) => results[idx] = val,
-                None => break
-            }
+#[test]
+fn test_spec_by() {
+    let close = |p: &f64, a: &f64| (*p - *a).abs() < 1e-9;
+
+    // 0.1 + 0.2 is not exactly 0.3, but is close enough to keep the guess
+    assert!(spec_by(|| 0.1f64 + 0.2, || 0.3f64, |x| x, close) == 0.3);
+    assert!(spec_by(|| 0.1f64 + 0.2, || 0.5f64, |x| x, close) == 0.1 + 0.2);
+    assert!(spec_by_key(|| (2, 'a'), || (2, 'b'), |(x, _)| x + 2, |&(x, _)| x) == 4);
+}
+
+#[test]
+fn test_spec_isolated() {
+    let consumer: &fn() -> ~fn(uint) -> uint = || { |x| [1u, 2, 3][x] };
+    assert!(spec_isolated(|| 2, || 2, consumer) == 3);
+    // Indexing with the wrong guess fails, but the guess is thrown away anyway
+    assert!(spec_isolated(|| 2, || 7, consumer) == 3);
+
+    let res = do task::try {
+        let consumer: &fn() -> ~fn(uint) -> uint = || { |x| [1u, 2, 3][x] };
+        spec_isolated(|| 7, || 7, consumer)
+    };
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_spec_cancellable() {
+    assert!(spec_cancellable(|| 2 + 2, || 4, |x, _| x + 2) == 6);
+
+    // A wrong guess spins until it is cancelled, so this only finishes if the
+    // producer cancels the speculative run.
+    let res = do spec_cancellable(|| 2 + 2, || 1) |x, token| {
+        if x != 4 {
+            while !token.is_cancelled() { task::deschedule(); }
         }
-        chan.send(results.clone());
-    }
+        x + 2
+    };
+    assert!(res == 6);
 }
 
 #[test]
-fn test_specfold_correct_prediction() {
-    let (port, chan): (Port<Option<(uint, int)>>, Chan<Option<(uint, int)>>) = stream();
-    let (res_port, res_chan) = stream();
-    let shared_chan = SharedChan::new(chan);
-    let shared_port = SharedPort::new(port);
+fn test_spec_any() {
+    let consumer: &fn() -> ~fn(int) -> int = || { |x| x + 2 };
+
+    let (stats, res) = spec_any(|| 2 + 2, || ~[1, 4, 7], consumer);
+    assert!(res == 6);
+    assert!(stats.winner == Some(1));
+    assert!(stats.mispredictions == ~[true, false, true]);
+
+    let (stats, res) = spec_any(|| 2 + 2, || ~[1, 2], consumer);
+    assert!(res == 6);
+    assert!(stats.winner == None);
+
+    let (stats, res) = spec_any(|| 2 + 2, || ~[], consumer);
+    assert!(res == 6);
+    assert!(stats.iters == 0);
+}
 
+#[test]
+fn test_spec_future() {
+    let res = spec_future(Future::from_fn(|| 2 + 2), || 4, |x| Future::from_value(x + 2));
+    assert!(res.unwrap() == 6);
+    let res = spec_future(Future::from_fn(|| 2 + 2), || 1, |x| Future::from_fn(|| x + 2));
+    assert!(res.unwrap() == 6);
+}
+
+#[test]
+fn test_spec_future_on() {
+    // Everything runs in this task, and only once the executor is driven
+    let exec = LocalExecutor::new();
+    let res = spec_future_on(&exec, Future::from_fn(|| 2 + 2), || 4, |x| Future::from_value(x + 2));
+    let other = spec_future_on(&exec, Future::from_value(4), || 1, |x| Future::from_fn(|| x * 3));
+    exec.run();
+    assert!(res.unwrap() == 6);
+    assert!(other.unwrap() == 12);
+    assert!(!exec.run_one());
+
+    // Blocking calls run a job whenever they would otherwise wait
+    assert!(spec_on(&exec, || 5, || 2, |x| x + 1, |p, a| *p == *a) == 6);
+}
+
+#[test]
+fn test_specfold_correct_prediction() {
     let loop_body: &fn() -> ~fn(uint, int) -> int = || {
-        let clone_chan = shared_chan.clone();
-        |idx:uint, val:int| {
-            let res = idx as int + val;
-            clone_chan.send(Some((idx, res)));
-            res
-        }
+        |idx:uint, val:int| idx as int + val
     };
 
     let loop_results = [0, 0, 1, 3, 6];
     let predictor: &fn() -> ~fn(uint) -> int = || { |idx| loop_results[idx] };
-    spawn_result_collector(shared_port.clone(), res_chan, 5);
-    specfold(5, loop_body, predictor);
-    shared_chan.send(None);
-    assert!(res_port.recv() == ~[0, 1, 3, 6, 10]);
+    let (stats, results) = specfold_collect(5, loop_body, predictor);
+    assert!(results == ~[0, 1, 3, 6, 10]);
+    assert!(stats.mispredictions.iter().all(|m| !*m));
 }
 
 #[test]
 fn test_specfold_incorrect_prediction() {
-    let (port, chan): (Port<Option<(uint, int)>>, Chan<Option<(uint, int)>>) = stream();
-    let (res_port, res_chan) = stream();
-    let shared_chan = SharedChan::new(chan);
-    let shared_port = SharedPort::new(port);
-
     let loop_body: &fn() -> ~fn(uint, int) -> int = || {
-        let clone_chan = shared_chan.clone();
-        |idx:uint, val:int| {
-            let res = idx as int + val;
-            clone_chan.send(Some((idx, res)));
-            res
+        |idx:uint, val:int| idx as int + val
+    };
+
+    let predictor: &fn() -> ~fn(uint) -> int = || { |_| 0 };
+    let (stats, results) = specfold_collect(5, loop_body, predictor);
+    assert!(results == ~[0, 1, 3, 6, 10]);
+    assert!(stats.mispredictions == ~[false, false, true, true, true]);
+}
+
+#[test]
+fn test_specfold_many_mispredictions() {
+    let loop_body: &fn() -> ~fn(uint, uint) -> uint = || { |idx, val| idx + val };
+    // Only every third prediction is right
+    let predictor: &fn() -> ~fn(uint) -> uint = || {
+        |idx| if idx % 3 == 0 && idx > 0 { idx * (idx - 1) / 2 } else { 0 }
+    };
+
+    let (stats, results) = specfold_collect(100, loop_body, predictor);
+    let mut expected = 0;
+    for (i, res) in results.iter().enumerate() {
+        expected += i;
+        assert!(*res == expected);
+        assert!(stats.mispredictions[i] == (i % 3 != 0 && i > 1));
+    }
+}
+
+#[test]
+fn test_worker_pool() {
+    let pool = WorkerPool::new(2);
+    assert!(spec_on(&pool, || 2 + 2, || 1, |x| x + 2, |p, a| *p == *a) == 6);
+
+    let loop_body: &fn() -> ~fn(uint, uint) -> uint = || { |idx, val| idx + val };
+    let predictor: &fn() -> ~fn(uint) -> uint = || { |_| 0 };
+    let (_, results) = specfold_on(&pool, 20, loop_body, predictor, |p, a| *p == *a);
+    assert!(results == vec::from_fn(20, |i| i * (i + 1) / 2));
+}
+
+#[test]
+fn test_adaptive() {
+    let mut adaptive = Adaptive::new();
+    assert!(adaptive.should_speculate());
+
+    let mut stats = SpecStats::new(4);
+    stats.mispredictions = ~[false, true, true, true];
+    adaptive.record(&stats, 1000);
+    assert!(adaptive.mispredict_rate() == 0.75);
+    assert!(adaptive.window(8) == 2 && adaptive.window(2) == 1);
+    // Sequential execution has not been measured yet
+    assert!(!adaptive.should_speculate());
+
+    let mut stats = SpecStats::new(4);
+    stats.sequential = true;
+    adaptive.record(&stats, 400);
+    assert!(!adaptive.should_speculate());
+
+    // Both ways produce the same results
+    let loop_body: &fn() -> ~fn(uint, int) -> int = || { |idx, val| idx as int + val };
+    let predictor: &fn() -> ~fn(uint) -> int = || { |_| 0 };
+    let (stats, results) = adaptive.specfold(5, loop_body, predictor);
+    assert!(stats.sequential);
+    assert!(results == ~[0, 1, 3, 6, 10]);
+    assert!(adaptive.spec(|| 2 + 2, || 1, |x| x + 2) == 6);
+
+    let mut adaptive = Adaptive::new();
+    assert!(adaptive.window(5) == 5);
+    let (stats, results) = adaptive.specfold(5, loop_body, predictor);
+    assert!(!stats.sequential);
+    assert!(results == ~[0, 1, 3, 6, 10]);
+}
+
+#[test]
+fn test_specfold_with_tolerance() {
+    let loop_body: &fn() -> ~fn(uint, f64) -> f64 = || { |_, val| val + 0.1 };
+    let predictor: &fn() -> ~fn(uint) -> f64 = || { |idx| idx as f64 * 0.1 };
+
+    let (stats, results) = do specfold_collect_by(10, loop_body, predictor) |p, a| {
+        (*p - *a).abs() < 1e-9
+    };
+    assert!(stats.mispredictions.iter().all(|m| !*m));
+    for (i, res) in results.iter().enumerate() {
+        assert!((*res - (i + 1) as f64 * 0.1).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn test_specfold_nan() {
+    // NaN is not equal to itself, so no run of a later iteration ever looks
+    // valid, but the re-execution on the committed result is still accepted
+    let loop_body: &fn() -> ~fn(uint, f64) -> f64 = || { |_, val| val + 1.0 };
+    let predictor: &fn() -> ~fn(uint) -> f64 = || { |idx| if idx == 0 { 0.0 / 0.0 } else { 0.0 } };
+    let (stats, results) = specfold_collect(4, loop_body, predictor);
+    assert!(results.len() == 4 && results.iter().all(|r| r.is_NaN()));
+    assert!(stats.mispredictions == ~[false, true, true, true]);
+}
+
+#[test]
+fn test_specfold_future() {
+    let loop_body: ~fn() -> ~fn(uint, int) -> int = || { |idx, val| idx as int + val };
+    let predictor: ~fn() -> ~fn(uint) -> int = || { |_| 0 };
+    let (_, results) = specfold_future(5, loop_body, predictor).unwrap();
+    assert!(results == ~[0, 1, 3, 6, 10]);
+}
+
+#[test]
+fn test_specfold_times() {
+    let loop_body: &fn() -> ~fn(uint, int) -> int = || { |idx, val| idx as int + val };
+    let predictor: &fn() -> ~fn(uint) -> int = || { |_| 0 };
+    let (stats, _) = specfold_collect(5, loop_body, predictor);
+
+    assert!(stats.times.len() == 5);
+    for (i, t) in stats.times.iter().enumerate() {
+        assert!(t.predictor.start <= t.predictor.end);
+        assert!(t.predictor.end <= t.body.start);
+        assert!(stats.span.start <= t.predictor.start);
+        assert!(t.body.end <= stats.span.end);
+        // Only mispredicted iterations need re-executing
+        assert!(stats.mispredictions[i] == (t.committed != 0));
+        if !stats.mispredictions[i] {
+            assert!(t.wasted_ns() == t.reexec_ns());
+        }
+    }
+    assert!(stats.wasted_ns() >= stats.times[2].body.ns());
+}
+
+#[test]
+fn test_trace() {
+    let (stats, res) = spec_timed(|| 2, || 3, |x| x * 10);
+    assert!(res == 20);
+    assert!(stats.mispredictions == ~[true]);
+    let timeline = lanes(&stats);
+    assert!(timeline.len() == 2);
+    assert!(timeline[0].phases.map(|p| p.wasted) == ~[false, true, false]);
+    assert!(timeline[1].name == ~"producer");
+
+    match trace_json(&stats) {
+        json::Object(ref obj) => match obj.find(&~"traceEvents") {
+            // The whole call, a name for each lane, and one event per phase
+            Some(&json::List(ref events)) => assert!(events.len() == 7),
+            _ => fail!()
+        },
+        _ => fail!()
+    }
+
+    let loop_body: &fn() -> ~fn(uint, int) -> int = || { |idx, val| idx as int + val };
+    let predictor: &fn() -> ~fn(uint) -> int = || { |_| 0 };
+    let (stats, _) = specfold_on(&TestExecutor::new(), 3, loop_body, predictor, |p, a| *p == *a);
+    let timeline = lanes(&stats);
+    assert!(timeline[0].name == ~"caller" && timeline[0].phases.is_empty());
+    let reexec = timeline.iter().find(|l| l.name == ~"iteration 2, run 1").unwrap();
+    assert!(reexec.phases[0].actual && !reexec.phases[0].wasted);
+    let spec = timeline.iter().find(|l| l.name == ~"iteration 2").unwrap();
+    assert!(spec.phases.map(|p| p.wasted) == ~[false, true]);
+}
+
+#[test]
+fn test_render_timeline() {
+    let (stats, _) = spec_timed(|| 2, || 3, |x| x * 10);
+    let text = render_timeline(&stats, 80);
+    let rows: ~[&str] = text.line_iter().collect();
+    assert!(rows.len() == 3);
+    assert!(rows.iter().all(|r| r.len() == 80));
+    assert!(rows[0].starts_with("caller:   "));
+    assert!(rows[1].starts_with("producer: "));
+    assert!(rows[2].starts_with("overall:  [") && rows[2].ends_with("]"));
+}
+
+#[test]
+fn test_specfold_failures() {
+    // Fail on anything but the real value
+    let loop_body: &fn() -> ~fn(uint, uint) -> uint = || {
+        |idx, val| {
+            assert!(val * 2 + idx == idx * idx);
+            idx + val
         }
     };
+    let predictor: &fn() -> ~fn(uint) -> uint = || { |_| 0 };
+    let (stats, results) = specfold_collect(5, loop_body, predictor);
+    assert!(results == ~[0, 1, 3, 6, 10]);
+    assert!(stats.failures == 3);
+
+    let res = do task::try {
+        let loop_body: &fn() -> ~fn(uint, int) -> int = || { |_, _| fail!("always fails") };
+        let predictor: &fn() -> ~fn(uint) -> int = || { |_| 0 };
+        specfold_collect(3, loop_body, predictor)
+    };
+    assert!(res.is_err());
+}
 
+#[test]
+fn test_specfold_output() {
+    let loop_body: &fn() -> ~fn(uint, int, &mut Output<(uint, int)>) -> int = || {
+        |idx, val, output| {
+            output.push((idx, val));
+            idx as int + val
+        }
+    };
     let predictor: &fn() -> ~fn(uint) -> int = || { |_| 0 };
-    spawn_result_collector(shared_port.clone(), res_chan, 5);
-    specfold(5, loop_body, predictor);
-    shared_chan.send(None);
-    assert!(res_port.recv() == ~[0, 1, 3, 6, 10]);
 
+    let (stats, results, output) = specfold_output(5, loop_body, predictor);
+    assert!(stats.mispredictions == ~[false, false, true, true, true]);
+    assert!(results == ~[0, 1, 3, 6, 10]);
+    // Nothing written by the mispredicted runs shows up
+    assert!(output == ~[(0, 0), (1, 0), (2, 1), (3, 3), (4, 6)]);
+
+    let mut published = ~[];
+    do specfold_output_on(&TaskExecutor, 5, loop_body, predictor, |p, a| *p == *a) |i, items| {
+        assert!(items.len() == 1);
+        published.push(i);
+    };
+    assert!(published == ~[0, 1, 2, 3, 4]);
 }
 
 #[test]
-fn test_specfold_single_task() {
-    let (port, chan): (Port<Option<(uint, int)>>, Chan<Option<(uint, int)>>) = stream();
-    let (res_port, res_chan): (Port<~[int]>, Chan<~[int]>) = stream();
-    let shared_chan = SharedChan::new(chan);
-    let shared_port = SharedPort::new(port);
+fn test_specfold_repair() {
+    // Emit the start of every token in [10 * idx, 10 * idx + 10), where tokens
+    // start at multiples of 5, so a run started anywhere is back in step
+    // after one token
+    let loop_body: &fn() -> ~fn(uint, uint, &mut Repair<uint, uint>) -> uint = || {
+        |idx, val, ctx| {
+            let mut pos = val;
+            while pos < 10 * (idx + 1) {
+                if ctx.checkpoint(&pos) { return 0 }
+                ctx.push(pos);
+                pos = (pos / 5 + 1) * 5;
+            }
+            pos
+        }
+    };
+    let predictor: &fn() -> ~fn(uint) -> uint = || { |idx| if idx == 0 { 0 } else { 10 * idx + 1 } };
 
-    let loop_body: &fn() -> ~fn(uint, int) -> int = || {
-        let clone_chan = shared_chan.clone();
-        |idx:uint, val:int| {
-            let res = idx as int + val + 5;
-            clone_chan.send(Some((idx, res)));
-            res
+    let (stats, results, output) = specfold_repair(4, loop_body, predictor);
+    assert!(results == ~[10, 20, 30, 40]);
+    assert!(output == ~[0, 5, 10, 15, 20, 25, 30, 35]);
+    assert!(stats.mispredictions == ~[false, true, true, true]);
+    assert!(stats.repairs == 3);
+}
+
+#[test]
+fn test_specfold_window() {
+    let loop_body: &fn() -> ~fn(uint, uint) -> uint = || { |idx, val| idx + val };
+    // Only every third prediction is right
+    let predictor: &fn() -> ~fn(uint) -> uint = || {
+        |idx| if idx % 3 == 0 && idx > 0 { idx * (idx - 1) / 2 } else { 0 }
+    };
+
+    let mut expected = 0;
+    let mut next = 0;
+    let stats = do specfold_window(4, 1000, loop_body, predictor) |i, res| {
+        assert!(i == next);
+        expected += i;
+        assert!(res == expected);
+        next += 1;
+    };
+    assert!(next == 1000);
+    assert!(stats.mispredictions[2] && !stats.mispredictions[3]);
+}
+
+#[test]
+fn test_stride_predictor() {
+    let loop_body: &fn() -> ~fn(uint, int) -> int = || { |_, val| val + 3 };
+    let stride = Stride::new(0, 1);
+
+    let (stats, results) = specfold_collect(5, loop_body, || stride.predict_fn());
+    assert!(results == ~[3, 6, 9, 12, 15]);
+    assert!(stats.mispredictions.iter().any(|m| *m));
+
+    stride.learn(results);
+    let (stats, results) = specfold_collect(5, loop_body, || stride.predict_fn());
+    assert!(results == ~[3, 6, 9, 12, 15]);
+    assert!(stats.mispredictions.iter().all(|m| !*m));
+}
+
+#[test]
+fn test_context_predictor() {
+    let loop_body: &fn() -> ~fn(uint, uint) -> uint = || { |_, val| (val + 1) % 3 };
+    let context = ContextTable::new(1, 0u);
+
+    let (stats, results) = specfold_collect(6, loop_body, || context.predict_fn());
+    assert!(results == ~[1, 2, 0, 1, 2, 0]);
+    assert!(stats.mispredictions.iter().any(|m| *m));
+
+    // The second time around, every value has been seen before
+    context.learn(results);
+    let (stats, results) = specfold_collect(6, loop_body, || context.predict_fn());
+    assert!(results == ~[1, 2, 0, 1, 2, 0]);
+    assert!(stats.mispredictions.iter().all(|m| !*m));
+
+    assert!(Constant::new(4).predict_fn()(10) == 4);
+}
+
+#[test]
+fn test_deterministic_schedules() {
+    let loop_body: &fn() -> ~fn(uint, int, &mut Output<uint>) -> int = || {
+        |idx, val, output| {
+            output.push(idx);
+            idx as int + val
         }
     };
+    // Always right, so only forced iterations mispredict
+    let predictor: &fn() -> ~fn(uint) -> int = || {
+        |idx| if idx == 0 { 0 } else { (idx * (idx - 1) / 2) as int }
+    };
+
+    let mut schedules = 0;
+    do for_each_schedule |exec| {
+        let (stats, results, output) =
+            specfold_forced(exec, 3, loop_body, predictor, |p, a| *p == *a, [1]);
+        assert!(stats.mispredictions == ~[false, true, false]);
+        assert!(stats.times[1].committed == 1);
+        assert!(results == ~[0, 1, 3]);
+        assert!(output == ~[0, 1, 2]);
+        schedules += 1;
+    }
+    assert!(schedules > 1);
+
+    let exec = TestExecutor::with_choices(~[1]);
+    assert!(spec_on(&exec, || 5, || 5, |x| x * 2, |p, a| *p == *a) == 10);
+    assert!(exec.branching() == ~[1]);
+}
+
+#[test]
+fn test_pipeline() {
+    let pipeline = Pipeline::new(|| |s: ~str| from_str::<int>(s).unwrap())
+        .then_spec(|| |x| x * 2, || 12)
+        .then_spec(|| |x| x + 1, || 0)
+        .then(|| |x| x.to_str());
+    let (stats, res) = pipeline.run(~"12");
+    assert!(res == ~"25");
+    assert!(stats.iters == 4);
+    assert!(stats.mispredictions == ~[false, false, true, false]);
+    // Only the stage with the wrong guess was run again
+    assert!(stats.times[1].reexecs.is_empty());
+    assert!(stats.times[2].committed == 1 && stats.times[2].reexecs.len() == 1);
+
+    // Stages which are never guessed can pass on values which cannot be compared
+    let pipeline = Pipeline::new(|| |x: int| Future::from_value(x))
+        .then(|| |f: Future<int>| f.unwrap() + 1);
+    let (_, res) = pipeline.run(2);
+    assert!(res == 3);
+}
+
+#[test]
+fn test_try_spec() {
+    let (stats, res) = try_spec(|| Ok(2), || 3, |x| if x == 3 { Err(~"guess") } else { Ok(x) });
+    assert!(res == Ok(2));
+    assert!(stats.errors == 1 && stats.error_at == None);
+
+    let (stats, res) = try_spec(|| Ok(2), || 2, |x| if x == 2 { Err(~"real") } else { Ok(x) });
+    assert!(res == Err(~"real"));
+    assert!(stats.errors == 0 && stats.error_at == Some(0));
+
+    let (_, res) = try_spec(|| Err::<int, ~str>(~"producer"), || 2, |x| Ok(x));
+    assert!(res == Err(~"producer"));
+
+    let (stats, res) = try_spec(|| Err::<int, ~str>(~"producer"), || 2, |_| Err(~"guess"));
+    assert!(res == Err(~"producer"));
+    assert!(stats.errors == 1 && stats.error_at == Some(0));
+}
+
+#[test]
+fn test_try_specfold() {
+    // Guesses are far too big, and big inputs are errors
+    let loop_body: &fn() -> ~fn(uint, int) -> Result<int, ~str> = || {
+        |idx, val| if val > 100 { Err(~"too big") } else { Ok(idx as int + val) }
+    };
+    let predictor: &fn() -> ~fn(uint) -> int = || { |idx| if idx == 0 { 0 } else { 1000 } };
+    let (stats, res) = try_specfold(5, loop_body, predictor);
+    assert!(res == Ok(~[0, 1, 3, 6, 10]));
+    // Only the speculative runs of the last four iterations fail
+    assert!(stats.errors == 4);
+    assert!(stats.error_at == None);
+
+    let loop_body: &fn() -> ~fn(uint, int) -> Result<int, ~str> = || {
+        |idx, val| if idx == 3 { Err(~"three") } else { Ok(idx as int + val) }
+    };
+    let (stats, res) = try_specfold(5, loop_body, || { |_| 0 });
+    assert!(res == Err(~"three"));
+    assert!(stats.error_at == Some(3));
+}
+
+#[test]
+fn test_memo() {
+    let mut memo = Memo::new(2);
+    assert!(memo.spec(|| 5, || 1, |x| x * 2) == 10);
+    assert!(memo.misses() == 2 && memo.hits() == 0);
+
+    // Both the guess and the real value were seen before
+    assert!(memo.spec(|| 5, || 1, |x| x * 2) == 10);
+    assert!(memo.misses() == 2 && memo.hits() == 2);
+
+    // Makes room by dropping the least recently used input
+    assert!(memo.spec(|| 7, || 7, |x| x * 2) == 14);
+    assert!(memo.len() == 2);
+    assert!(memo.find(&1) == None);
+    assert!(memo.find(&5) == Some(10));
+}
+
+#[test]
+fn test_budget() {
+    let budget = Budget::new(3);
+    let inner = budget.clone();
+    // Each iteration speculates over a loop of its own
+    let loop_body: &fn() -> ~fn(uint, uint) -> uint = || {
+        let inner = inner.clone();
+        |idx, val| {
+            let body: &fn() -> ~fn(uint, uint) -> uint = || { |i, v| i + v };
+            let (stats, results) = inner.specfold(4, body, || { |_| 0 });
+            // The outer loop holds every slot
+            assert!(stats.sequential && stats.slots == 0);
+            val + idx + results[3]
+        }
+    };
+    let predictor: &fn() -> ~fn(uint) -> uint = || { |_| 0 };
+
+    let (stats, results) = budget.specfold(3, loop_body, predictor);
+    assert!(results == ~[6, 13, 21]);
+    assert!(!stats.sequential && stats.slots == 3);
+    assert!(budget.available() == 3);
+
+    let (stats, res) = budget.spec(|| 2, || 2, |x| x + 1);
+    assert!(res == 3 && stats.slots == 1);
+
+    // A failing call gives its slots back too
+    let failing = budget.clone();
+    let res = do task::try {
+        let (_, res) = failing.spec(|| 2, || 2, |x: uint| [1u, 2][x]);
+        res
+    };
+    assert!(res.is_err());
+    assert!(budget.available() == 3);
+}
+
+// Running sums, carrying the total so far from chunk to chunk
+fn sum_chunk(input: &Arc<~[int]>, lo: uint, hi: uint, total: int, output: &mut Output<int>) -> int {
+    let mut total = total;
+    for x in input.get().slice(lo, hi).iter() {
+        total += *x;
+        output.push(total);
+    }
+    total
+}
+
+fn guess_chunk_zero(_: &Arc<~[int]>, _: uint) -> int { 0 }
+
+#[test]
+fn test_specfold_chunks() {
+    assert!(chunk_bounds(7, 3, 2) == (6, 7));
+    assert!(chunk_bounds(2, 4, 3) == (2, 2));
+
+    let step: &fn() -> ~fn(&Arc<~[int]>, uint, uint, int, &mut Output<int>) -> int = || {
+        |input, lo, hi, total, output| sum_chunk(input, lo, hi, total, output)
+    };
+    let predictor: &fn() -> ~fn(&Arc<~[int]>, uint) -> int = || { |_, _| 0 };
+
+    let (stats, output) = specfold_chunks(Arc::new(~[1, 2, 3, 4, 5, 6, 7]), 3, step, predictor);
+    assert!(output == ~[1, 3, 6, 10, 15, 21, 28]);
+    assert!(stats.mispredictions == ~[false, true, true]);
+
+    let (_, output) = specfold_chunks_shared(Arc::new(~[1, 2, 3, 4, 5, 6, 7]), 3, sum_chunk,
+                                             guess_chunk_zero);
+    assert!(output == ~[1, 3, 6, 10, 15, 21, 28]);
+}
+
+fn add_step(steps: &~[int], idx: uint, val: int) -> int { val + steps[idx] }
+
+fn guess_zero(_: &~[int], _: uint) -> int { 0 }
+
+#[test]
+fn test_specfold_shared() {
+    let steps = Arc::new(~[1, 2, 3, 4]);
+    let (stats, results) = specfold_shared(4, steps, add_step, guess_zero);
+    assert!(results == ~[1, 3, 6, 10]);
+    assert!(stats.mispredictions == ~[false, true, true, true]);
+}
+
+#[test]
+fn test_spec_while() {
+    let loop_body: &fn() -> ~fn(uint, int) -> int = || { |_, val| val + 3 };
+    let cond: &fn() -> ~fn(&int) -> bool = || { |s| *s < 10 };
+
+    let exact: &fn() -> ~fn(uint) -> Option<int> = || {
+        |idx| if idx <= 3 { Some(3 * idx as int) } else { None }
+    };
+    let (stats, results) = spec_while(0, 8, cond, loop_body, exact);
+    assert!(results == ~[3, 6, 9, 12]);
+    assert!(stats.iters == 4);
+    assert!(stats.mispredictions.iter().all(|m| !*m));
+
+    // Runs past the end of the loop are thrown away
+    let (stats, results) = spec_while(0, 3, cond, loop_body, || { |_| Some(0) });
+    assert!(results == ~[3, 6, 9, 12]);
+    assert!(stats.mispredictions == ~[false, true, true, true]);
+
+    // Guessing the loop ends too soon counts as a misprediction
+    let early: &fn() -> ~fn(uint) -> Option<int> = || {
+        |idx| if idx < 2 { Some(3 * idx as int) } else { None }
+    };
+    let (stats, results) = spec_while(0, 8, cond, loop_body, early);
+    assert!(results == ~[3, 6, 9, 12]);
+    assert!(stats.mispredictions == ~[false, false, true, true]);
+
+    let (stats, results) = spec_while(20, 8, cond, loop_body, exact);
+    assert!(results == ~[] && stats.iters == 0);
+
+    // The same iterations are committed whatever order the runs finish in
+    do for_each_schedule |exec| {
+        let (stats, results) = spec_while_on(exec, 0, 2, cond, loop_body, early,
+                                             |p, a| *p == *a);
+        assert!(results == ~[3, 6, 9, 12]);
+        assert!(stats.mispredictions == ~[false, false, true, true]);
+    }
+}
+
+// Emits the position in the word of every letter, and a 0 at every
+// space after a word
+fn word_fsm() -> Fsm<uint> {
+    let mut fsm = Fsm::new(4, 0);
+    for c in range('a' as u8, 'z' as u8 + 1) {
+        fsm.set(0, c, 1, Some(1));
+        fsm.set(1, c, 2, Some(2));
+        fsm.set(2, c, 3, Some(3));
+        fsm.set(3, c, 3, Some(3));
+    }
+    for s in range(1u, 4) {
+        fsm.set(s, ' ' as u8, 0, Some(0));
+    }
+    fsm
+}
+
+#[test]
+fn test_fsm() {
+    let text = "ab cd  efgh i jkl mn".as_bytes().to_owned();
+    let fsm = word_fsm();
+    let mut expected = ~[];
+    let end = fsm.run(0, text, &mut expected);
+    let fsm = Arc::new(fsm);
+    let input = Arc::new(text);
+
+    let (stats, spec_end, outputs) = fsm_spec(fsm.clone(), input.clone(), 0, 4, || { |_, _| 0 });
+    assert!(spec_end == end);
+    assert!(outputs.len() == 4);
+    assert!(outputs.concat_vec() == expected);
+    assert!(stats.mispredictions.iter().any(|m| *m));
+
+    let guesser = fsm.clone();
+    let (_, spec_end, outputs) = fsm_spec(fsm.clone(), input.clone(), 0, 4, || {
+        let fsm = guesser.clone();
+        |input, lo| fsm.get().guess_state(input, lo, 3, 0)
+    });
+    assert!(spec_end == end && outputs.concat_vec() == expected);
+
+    let (stats, enum_end, outputs) = fsm_enumerate(fsm, input, 0, 4);
+    assert!(enum_end == end && outputs.concat_vec() == expected);
+    assert!(stats.mispredictions.iter().all(|m| !*m));
+}
+
+#[test]
+fn test_specfold_single_task() {
+    let loop_body: &fn() -> ~fn(uint, int) -> int = || {
+        |idx:uint, val:int| idx as int + val + 5
+    };
 
     let predictor: &fn() -> ~fn(uint) -> int = || { |_| 0 };
-    spawn_result_collector(shared_port.clone(), res_chan, 1);
-    specfold(1, loop_body, predictor);
-    shared_chan.send(None);
-    assert!(res_port.recv() == ~[5]);
+    let (_, results) = specfold_collect(1, loop_body, predictor);
+    assert!(results == ~[5]);
 }
 
 #[test]
 fn test_specfold_no_tasks() {
-    let (port, chan): (Port<Option<(uint, int)>>, Chan<Option<(uint, int)>>) = stream();
-    let (res_port, res_chan): (Port<~[int]>, Chan<~[int]>) = stream();
-    let shared_chan = SharedChan::new(chan);
-    let shared_port = SharedPort::new(port);
-
     let loop_body: &fn() -> ~fn(uint, int) -> int = || {
-        let clone_chan = shared_chan.clone();
-        |idx:uint, val:int| {
-            let res = idx as int + val + 5;
-            clone_chan.send(Some((idx, res)));
-            res
-        }
+        |idx:uint, val:int| idx as int + val + 5
     };
 
     let predictor: &fn() -> ~fn(uint) -> int = || { |_| 0 };
-    spawn_result_collector(shared_port.clone(), res_chan, 0);
-    specfold(0, loop_body, predictor);
-    shared_chan.send(None);
-    assert!(res_port.recv() == ~[]);
+    let (stats, results) = specfold_collect(0, loop_body, predictor);
+    assert!(results == ~[]);
+    assert!(stats.iters == 0);
 }
@@ -0,0 +1,238 @@
+use std::{io, num, os, str, vec};
+use extra::json;
+use extra::json::ToJson;
+use extra::treemap::TreeMap;
+
+use super::{Span, SpecStats};
+
+/**
+ * One part of a speculative call, such as a run of the predictor or of the
+ * loop body.
+ */
+#[deriving(Clone)]
+pub struct Phase {
+    // "producer", "predictor", "consumer" or "loop body"
+    name: ~str,
+    iter: uint,
+    // 0 for the run on the guess, otherwise which re-execution this was
+    run: uint,
+    span: Span,
+    // Whether the result was the real input rather than a guess
+    actual: bool,
+    // Whether the result was thrown away
+    wasted: bool
+}
+
+/**
+ * The phases which ran in one task, in the order they started.
+ */
+#[deriving(Clone)]
+pub struct Lane {
+    name: ~str,
+    phases: ~[Phase]
+}
+
+/**
+ * Split the times recorded in `stats` into the tasks they ran in.
+ *
+ * The first lane is always the calling task. For `spec_timed` it runs the
+ * predictor and the consumer, and the producer gets a lane of its own. For the
+ * `specfold` family every run of an iteration is in its own lane, unless the
+ * loop was run sequentially. Parts which never ran are left out.
+ */
+pub fn lanes(stats: &SpecStats) -> ~[Lane] {
+    let mut caller = Lane { name: ~"caller", phases: ~[] };
+    let mut others = ~[];
+
+    match stats.producer {
+        Some(ref span) => {
+            let t = &stats.times[0];
+            caller.phases.push(phase("predictor", 0, 0, &t.predictor, false, false));
+            caller.phases.push(phase("consumer", 0, 0, &t.body, false, t.committed != 0));
+            for (k, span) in t.reexecs.iter().enumerate() {
+                caller.phases.push(phase("consumer", 0, k + 1, span, true, false));
+            }
+            others.push(Lane { name: ~"producer",
+                               phases: ~[phase("producer", 0, 0, span, true, false)] });
+        }
+        None => {
+            for (i, t) in stats.times.iter().enumerate() {
+                let mut first = Lane { name: format!("iteration {}", i), phases: ~[] };
+                first.phases.push(phase("predictor", i, 0, &t.predictor, false, false));
+                first.phases.push(phase("loop body", i, 0, &t.body, i == 0, t.committed != 0));
+                if stats.sequential {
+                    caller.phases.push_all_move(first.phases);
+                } else {
+                    others.push(first);
+                }
+
+                for (k, span) in t.reexecs.iter().enumerate() {
+                    let run = phase("loop body", i, k + 1, span, true, t.committed != k + 1);
+                    if stats.sequential {
+                        caller.phases.push(run);
+                    } else {
+                        others.push(Lane { name: format!("iteration {}, run {}", i, k + 1),
+                                           phases: ~[run] });
+                    }
+                }
+            }
+        }
+    }
+
+    for lane in others.mut_iter() {
+        lane.phases.retain(|p| p.span.start != 0);
+    }
+    caller.phases.retain(|p| p.span.start != 0);
+    let mut res = ~[caller];
+    res.push_all_move(others.move_iter().filter(|l| !l.phases.is_empty()).collect());
+    res
+}
+
+fn phase(name: &str, iter: uint, run: uint, span: &Span, actual: bool, wasted: bool) -> Phase {
+    Phase { name: name.to_owned(), iter: iter, run: run, span: span.clone(), actual: actual,
+            wasted: wasted }
+}
+
+fn object(fields: ~[(~str, json::Json)]) -> json::Json {
+    let mut map = ~TreeMap::new();
+    for (key, value) in fields.move_iter() {
+        map.insert(key, value);
+    }
+    json::Object(map)
+}
+
+// Microseconds since `base`, as trace viewers expect
+fn micros(base: u64, ns: u64) -> json::Json {
+    json::Number((ns - base) as float / 1_000f)
+}
+
+/**
+ * The times recorded in `stats` in the Chrome trace-event format, which can be
+ * loaded into `chrome://tracing` or other trace viewers. Each lane is a
+ * thread, and the whole call is an event on the calling thread.
+ */
+pub fn trace_json(stats: &SpecStats) -> json::Json {
+    let base = stats.span.start;
+    let mut events = ~[];
+    let event = |name: &str, tid: uint, span: &Span, args: json::Json| {
+        object(~[(~"name", name.to_json()),
+                 (~"cat", (~"speculate").to_json()),
+                 (~"ph", (~"X").to_json()),
+                 (~"pid", 0u.to_json()),
+                 (~"tid", tid.to_json()),
+                 (~"ts", micros(base, span.start)),
+                 (~"dur", micros(span.start, span.end)),
+                 (~"args", args)])
+    };
+
+    events.push(event("call", 0, &stats.span,
+                      object(~[(~"iters", stats.iters.to_json()),
+                               (~"mispredictions",
+                                stats.mispredictions.iter().count(|m| *m).to_json())])));
+    for (tid, lane) in lanes(stats).iter().enumerate() {
+        events.push(object(~[(~"name", (~"thread_name").to_json()),
+                             (~"ph", (~"M").to_json()),
+                             (~"pid", 0u.to_json()),
+                             (~"tid", tid.to_json()),
+                             (~"args", object(~[(~"name", lane.name.to_json())]))]));
+        for p in lane.phases.iter() {
+            events.push(event(p.name, tid, &p.span,
+                              object(~[(~"iter", p.iter.to_json()),
+                                       (~"run", p.run.to_json()),
+                                       (~"actual", p.actual.to_json()),
+                                       (~"wasted", p.wasted.to_json())])));
+        }
+    }
+
+    object(~[(~"traceEvents", json::List(events)),
+             (~"displayTimeUnit", (~"ns").to_json())])
+}
+
+/**
+ * Write `trace_json(stats)` to the file at `path`.
+ */
+pub fn write_trace(stats: &SpecStats, path: &Path) -> Result<(), ~str> {
+    match io::file_writer(path, [io::Create, io::Truncate]) {
+        Ok(writer) => {
+            trace_json(stats).to_writer(writer);
+            Ok(())
+        }
+        Err(e) => Err(e)
+    }
+}
+
+/**
+ * The width of the terminal, from `$COLUMNS`, or 80 if that is not set.
+ */
+pub fn terminal_width() -> uint {
+    match os::getenv("COLUMNS") {
+        Some(cols) => from_str::<uint>(cols).unwrap_or(80),
+        None => 80
+    }
+}
+
+/**
+ * Draw the lanes of `stats` as ASCII bars, like the timelines in the README,
+ * fitting each row into `width` columns.
+ *
+ * Each phase is labelled with what ran (`p` for the producer, `g` for the
+ * predictor, `c` for the consumer or loop body) and, for consumers, whether
+ * it was given the guess (`c g`) or the real value (`c a`). Runs whose result
+ * was thrown away are filled with `x`, and re-executions with `=`.
+ */
+pub fn render_timeline(stats: &SpecStats, width: uint) -> ~str {
+    let timeline = lanes(stats);
+    let name_width = timeline.iter().fold(7, |w, lane| num::max(w, lane.name.len()));
+    let bar_width = num::max(width - num::min(width, name_width + 2), 10);
+    let base = stats.span.start;
+    let total = num::max(stats.span.ns(), 1);
+    let column = |ns: u64| {
+        let col = ((num::max(ns, base) - base) * (bar_width as u64 - 1) / total) as uint;
+        num::min(col, bar_width - 1)
+    };
+
+    let mut out = ~"";
+    let row = |name: &str, phases: &[(Span, char, ~str)]| {
+        let mut bars = vec::from_elem(bar_width, ' ');
+        for &(ref span, fill, ref label) in phases.iter() {
+            draw(bars.mut_slice(0, bar_width), column(span.start), column(span.end), fill,
+                 label.as_slice());
+        }
+        format!("{}: {}{}\n", name, " ".repeat(name_width - name.len()), str::from_chars(bars))
+    };
+
+    for lane in timeline.iter() {
+        let phases: ~[(Span, char, ~str)] = do lane.phases.iter().map |p| {
+            let fill = if p.wasted { 'x' } else if p.run > 0 { '=' } else { '-' };
+            let label = match p.name.as_slice() {
+                "producer" => ~"p",
+                "predictor" => ~"g",
+                _ => if p.actual { ~"c a" } else { ~"c g" }
+            };
+            (p.span.clone(), fill, label)
+        }.collect();
+        out.push_str(row(lane.name, phases));
+    }
+    out.push_str(row("overall", [(stats.span.clone(), '-', ~"call")]));
+    out
+}
+
+// Draw a bar from column `from` to `to` inclusive, with `label` in the middle
+// if it fits.
+fn draw(bars: &mut [char], from: uint, to: uint, fill: char, label: &str) {
+    let from = num::min(from, bars.len() - 2);
+    let to = num::min(num::max(to, from + 1), bars.len() - 1);
+    bars[from] = '[';
+    bars[to] = ']';
+    for col in range(from + 1, to) {
+        bars[col] = fill;
+    }
+
+    let inside = to - from - 1;
+    if inside >= label.len() + 2 {
+        let at = from + 1 + (inside - label.len()) / 2;
+        for k in range(0, label.len()) {
+            bars[at + k] = label.char_at(k);
+        }
+    }
+}
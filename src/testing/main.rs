@@ -8,7 +8,7 @@ use std::cell::Cell;
 use std::{task, os, from_str};
 use css_lex::*;
 use extra::time::precise_time_ns;
-use speculate::SpecStats;
+use speculate::{SpecStats, render_timeline, terminal_width, write_trace};
 
 fn bench<T>(inner: &fn() -> T) -> (T, u64) {
     let ns_start = precise_time_ns();
@@ -54,13 +54,27 @@ fn main() {
         let seq_time = seq_tokenize(file.clone());
         let par_time = par_tokenize(file.clone());
         match (seq_time, par_time) {
-            (Ok((_, s)), Ok((p_stats, p_time))) =>
+            (Ok((_, s)), Ok((p_stats, p_time))) => {
                 println!("{},{:.4f},{:.4f},{},{}",
                          file.filename().unwrap(),
                          s as float / 1_000f,
                          p_time as float / 1_000f,
                          file.get_size().unwrap(),
-                         p_stats.mispredictions.iter().count(|e| *e)),
+                         p_stats.mispredictions.iter().count(|e| *e));
+                if os::getenv("SPEC_TIMELINE").is_some() {
+                    std::io::stderr().write_str(format!("{}:\n{}\n", file.filename().unwrap(),
+                                                        render_timeline(&p_stats,
+                                                                        terminal_width())));
+                }
+                // Write a trace of each parallel run if given a directory
+                if os::args().len() > 2 {
+                    let trace = Path(os::args()[2]).push(file.filename().unwrap() + ".json");
+                    match write_trace(&p_stats, &trace) {
+                        Ok(()) => (),
+                        Err(e) => error!("could not write {}: {}", trace.to_str(), e)
+                    }
+                }
+            }
             _ => (),
         }
     }